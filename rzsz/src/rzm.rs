@@ -4,25 +4,303 @@ extern crate log;
 extern crate env_logger;
 extern crate clap;
 
-mod read_write;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, UNIX_EPOCH};
+use clap::{Arg, ArgGroup, App};
+use zmodem::{Conversion, Escape, FileInfo, Management, Transport};
+use zmodem::capture::{Capture, Format};
+use zmodem::recv::{Action, Options};
 
-use std::fs::File;
-use std::path::Path;
-use clap::{Arg, App};
+/// What to do when an incoming file already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Policy {
+    /// Follow the management option sent in ZFILE, skip by default
+    Sender,
+    Overwrite,
+    Append,
+    Rename,
+    Resume,
+    Protect,
+}
 
-fn main() {
+#[tokio::main]
+async fn main() -> ExitCode {
     env_logger::init().unwrap();
 
     let matches = App::new("Pure Rust implementation of rz utility")
-        .arg(Arg::with_name("file")
+        .arg(Arg::with_name("dir")
+             .help("Directory to store received files in")
              .required(false)
              .index(1))
+        .arg(Arg::with_name("overwrite")
+             .help("Overwrite existing files")
+             .short("y")
+             .long("overwrite"))
+        .arg(Arg::with_name("rename")
+             .help("Store under a new name if the file exists")
+             .short("E")
+             .long("rename"))
+        .arg(Arg::with_name("resume")
+             .help("Resume interrupted transfers of existing files")
+             .short("r")
+             .long("resume"))
+        .arg(Arg::with_name("protect")
+             .help("Never overwrite existing files, even if the sender asks to")
+             .short("p")
+             .long("protect"))
+        .arg(Arg::with_name("bufsize")
+             .help("Receive buffer size advertised to the sender, limits its subpackets and window")
+             .short("B")
+             .long("bufsize")
+             .takes_value(true))
+        .arg(Arg::with_name("escape-all")
+             .help("Ask the sender to escape all control characters")
+             .short("e")
+             .long("escape-all"))
+        .arg(Arg::with_name("7bit")
+             .help("Ask the sender to escape bytes with the 8th bit set for 7 bit lines, an extension szm understands")
+             .short("7")
+             .long("7bit"))
+        .arg(Arg::with_name("rle")
+             .help("Accept files compressed with run-length encoding (ZTRLE)")
+             .long("rle"))
+        .arg(Arg::with_name("lzw")
+             .help("Accept files compressed with the LZW extension of szm -Z")
+             .short("Z")
+             .long("lzw"))
+        .arg(Arg::with_name("trace")
+             .help("Write every byte exchanged with the remote to a JSON lines file")
+             .short("T")
+             .long("trace")
+             .takes_value(true))
+        .arg(Arg::with_name("verbose")
+             .help("Print transfer statistics")
+             .short("v")
+             .long("verbose"))
+        .group(ArgGroup::with_name("policy")
+               .args(&["overwrite", "rename", "resume", "protect"]))
         .get_matches();
 
-    let fileopt = matches.value_of("file").unwrap_or("rz-out");
-    let filename = Path::new(fileopt).file_name().unwrap().clone();
-    let file = File::create(filename).expect(&format!("Cannot create file {:?}:", filename));
+    let dir = PathBuf::from(matches.value_of("dir").unwrap_or("."));
+    let policy =
+        if matches.is_present("overwrite")    { Policy::Overwrite }
+        else if matches.is_present("rename")  { Policy::Rename }
+        else if matches.is_present("resume")  { Policy::Resume }
+        else if matches.is_present("protect") { Policy::Protect }
+        else                                  { Policy::Sender };
+
+    let options = Options {
+        buffer_size: match matches.value_of("bufsize").map(|x| x.parse::<u16>()) {
+            Some(Ok(x))  => x,
+            Some(Err(e)) => clap::Error::with_description(&format!("invalid buffer size: {}", e), clap::ErrorKind::InvalidValue).exit(),
+            None         => 0,
+        },
+        escape: Escape {
+            controls:  matches.is_present("escape-all"),
+            eight_bit: matches.is_present("7bit"),
+            ..Escape::MINIMAL
+        },
+        transports: [(Transport::Rle, "rle"), (Transport::Lzw, "lzw")].into_iter()
+            .filter(|(_, arg)| matches.is_present(arg))
+            .map(|(x, _)| x)
+            .collect(),
+        ..Options::default()
+    };
+
+    let mut paths = HashMap::new();
+    let trace: Box<dyn Write + Send> = match matches.value_of("trace") {
+        Some(path) => match File::create(path) {
+            Ok(x)  => Box::new(BufWriter::new(x)),
+            Err(e) => {
+                eprintln!("rzm: {}: {}", path, e);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => Box::new(io::sink()),
+    };
+    let inout = zmodem::AsyncReadWrite::new(tokio::io::stdin(), tokio::io::stdout());
+    let inout = Capture::new(inout, trace, Format::JsonLines);
+
+    let result = zmodem::recv::recv_files(inout, |info| {
+        match open(&dir, policy, info) {
+            Ok(Some((file, offset, path))) => {
+                paths.insert(info.name.clone(), path);
+                Action::Accept(tokio::fs::File::from_std(file), offset)
+            },
+            Ok(None) => {
+                eprintln!("rzm: skipping {}", info.name);
+                Action::Skip
+            },
+            Err(e) => {
+                eprintln!("rzm: {}: {}", info.name, e);
+                Action::Skip
+            },
+        }
+    }, &options).await;
+
+    match result {
+        Ok((files, stats)) => {
+            if matches.is_present("verbose") {
+                eprintln!("rzm: {} files, {} bytes received, {} CRC errors, {} bad headers, {} retransmission requests",
+                          stats.files, stats.bytes, stats.crc_errors, stats.bad_headers, stats.rewinds);
+            }
+            for info in files {
+                if let Some(path) = paths.get(&info.name) {
+                    if let Err(e) = set_attributes(path, &info) {
+                        eprintln!("rzm: {}: {}", path.display(), e);
+                    }
+                }
+            }
+            ExitCode::SUCCESS
+        },
+        Err(e) => {
+            eprintln!("rzm: {}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Opens the local file for an incoming one.
+/// Returns the file, the offset to start receiving at and its path, or None to skip it.
+fn open(dir: &Path, policy: Policy, info: &FileInfo) -> io::Result<Option<(File, u32, PathBuf)>> {
+    let path = match sanitize(dir, &info.name) {
+        Some(x) => x,
+        None    => return Ok(None),
+    };
+
+    check_links(dir, &path)?;
+
+    let meta = match fs::symlink_metadata(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) if info.skip_if_absent => return Ok(None),
+        Err(_) => return create(path).map(Some),
+    };
+
+    let policy = match policy {
+        Policy::Sender => sender_policy(info, &meta),
+        x              => x,
+    };
+
+    match policy {
+        Policy::Overwrite => create(path).map(Some),
+        Policy::Append    => {
+            let file = OpenOptions::new().append(true).open(&path)?;
+            Ok(Some((file, 0, path)))
+        },
+        Policy::Rename    => {
+            let path = (1..)
+                .map(|i| path.with_file_name(format!("{}.{}", path.file_name().unwrap().to_string_lossy(), i)))
+                .find(|x| fs::symlink_metadata(x).is_err())
+                .unwrap();
+            create(path).map(Some)
+        },
+        // the length on disk isn't the length sent
+        Policy::Resume if info.conversion == Conversion::Newline => create(path).map(Some),
+        Policy::Resume    => {
+            let len = match u32::try_from(meta.len()) {
+                Ok(x) if info.size.is_none_or(|size| x < size) => x,
+                _ => return Ok(None), // already complete
+            };
+            let file = OpenOptions::new().append(true).open(&path)?;
+            Ok(Some((file, len, path)))
+        },
+        Policy::Sender | Policy::Protect => Ok(None),
+    }
+}
+
+/// Resolves the management option of ZFILE against an existing file
+fn sender_policy(info: &FileInfo, meta: &Metadata) -> Policy {
+    let newer = match (info.mtime, meta.modified()) {
+        (Some(mtime), Ok(local)) => UNIX_EPOCH + Duration::from_secs(mtime) > local,
+        _                        => true,
+    };
+    let longer = info.size.is_none_or(|x| x as u64 > meta.len());
+    let different = info.size.is_none_or(|x| x as u64 != meta.len()) ||
+        info.mtime.is_some_and(|x| meta.modified().ok() != Some(UNIX_EPOCH + Duration::from_secs(x)));
+
+    let replace = match info.management {
+        Management::Clobber       => true,
+        Management::Append        => return Policy::Append,
+        Management::Rename        => return Policy::Rename,
+        Management::Newer         => newer,
+        Management::NewerOrLonger => newer || longer,
+        Management::Crc           => different,
+        Management::Different     => different,
+        _                         => false,
+    };
+
+    if replace {
+        Policy::Overwrite
+    }
+    else if info.conversion == Conversion::Resume {
+        Policy::Resume
+    }
+    else {
+        Policy::Protect
+    }
+}
+
+fn create(path: PathBuf) -> io::Result<(File, u32, PathBuf)> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    File::create(&path).map(|file| (file, 0, path))
+}
+
+/// Refuses paths through symbolic links below `dir`, which could point
+/// out of it
+fn check_links(dir: &Path, path: &Path) -> io::Result<()> {
+    for x in path.ancestors().take_while(|x| *x != dir) {
+        match fs::symlink_metadata(x) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a symbolic link", x.display())));
+            },
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Maps the transmitted pathname below `dir`, dropping root,
+/// drive prefixes and ".." components so nothing escapes it
+fn sanitize(dir: &Path, name: &str) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    let parts = Path::new(&name)
+        .components()
+        .filter_map(|x| match x {
+            Component::Normal(x) => Some(x),
+            _                    => None,
+        })
+        .collect::<Vec<_>>();
+
+    if parts.is_empty() {
+        return None;
+    }
+
+    let mut path = dir.to_path_buf();
+    path.extend(parts);
+    Some(path)
+}
+
+/// Applies modification time and permissions sent in ZFILE
+fn set_attributes(path: &Path, info: &FileInfo) -> io::Result<()> {
+    if let Some(mtime) = info.mtime {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = info.mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+    }
 
-    let inout = read_write::AsyncReadWrite::new();
-    zmodem::recv::recv(inout, file).unwrap();
+    Ok(())
 }
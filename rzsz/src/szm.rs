@@ -4,29 +4,312 @@ extern crate log;
 extern crate env_logger;
 extern crate clap;
 
-mod read_write;
+use std::collections::HashSet;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+use clap::{Arg, ArgGroup, App};
+use zmodem::{Conversion, Escape, FileInfo, Management, Transport};
+use zmodem::capture::{Capture, Format};
+use zmodem::send::{Options, Protocol};
 
-use std::fs::File;
-use std::path::Path;
-use clap::{Arg, App};
+/// Exit status of lrzsz's sz when the transfer fails
+const EXIT_TRANSFER_FAILED: u8 = 0o200;
+/// Exit status of lrzsz's sz when some files couldn't be sent
+const EXIT_FILE_ERRORS: u8 = 1;
+/// Longest subpacket receivers accept, the largest of ZMODEM
+const MAX_BLOCK_SIZE: usize = 8192;
+
+/// File to send with the pathname transmitted for it
+struct Entry {
+    path: PathBuf,
+    name: String,
+    meta: Metadata,
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     env_logger::init().unwrap();
 
     let matches = App::new("Pure Rust implementation of sz utility")
         .arg(Arg::with_name("file")
+             .help("Files, directories or glob patterns to send")
              .required(true)
+             .multiple(true)
              .index(1))
+        .arg(Arg::with_name("resume")
+             .help("Ask the receiver to resume interrupted transfers")
+             .short("r")
+             .long("resume"))
+        .arg(Arg::with_name("block-size")
+             .help("Data subpacket size in bytes")
+             .short("L")
+             .long("block-size")
+             .takes_value(true))
+        .arg(Arg::with_name("window")
+             .help("Bytes sent before waiting for an acknowledgement")
+             .short("w")
+             .long("window")
+             .takes_value(true))
+        .arg(Arg::with_name("crc16")
+             .help("Use 16 bit CRC instead of 32 bit")
+             .short("o")
+             .long("crc16"))
+        .arg(Arg::with_name("escape-all")
+             .help("Escape all control characters")
+             .short("e")
+             .long("escape-all"))
+        .arg(Arg::with_name("telnet")
+             .help("Escape CR after '@' for telnet and modem lines")
+             .long("telnet"))
+        .arg(Arg::with_name("7bit")
+             .help("Escape bytes with the 8th bit set for 7 bit lines, an extension rzm understands")
+             .short("7")
+             .long("7bit"))
+        .arg(Arg::with_name("adaptive")
+             .help("Shrink subpackets after errors and grow them back after clean runs")
+             .short("a")
+             .long("adaptive"))
+        .arg(Arg::with_name("xon-xoff")
+             .help("Pause on XOFF from the line until XON")
+             .short("x")
+             .long("xon-xoff"))
+        .arg(Arg::with_name("rle")
+             .help("Compress runs of a byte if the receiver can uncompress")
+             .long("rle"))
+        .arg(Arg::with_name("lzw")
+             .help("Compress with the LZW extension if the receiver can uncompress, e.g. rzm -Z")
+             .short("Z")
+             .long("lzw"))
+        .arg(Arg::with_name("trace")
+             .help("Write every byte exchanged with the remote to a JSON lines file")
+             .short("T")
+             .long("trace")
+             .takes_value(true))
+        .arg(Arg::with_name("verbose")
+             .help("Print transfer statistics")
+             .short("v")
+             .long("verbose"))
+        .arg(Arg::with_name("overwrite")
+             .help("Ask the receiver to overwrite existing files")
+             .short("y")
+             .long("overwrite"))
+        .arg(Arg::with_name("append")
+             .help("Ask the receiver to append to existing files")
+             .short("+")
+             .long("append"))
+        .group(ArgGroup::with_name("management")
+               .args(&["overwrite", "append"]))
+        .group(ArgGroup::with_name("transport")
+               .args(&["rle", "lzw"]))
         .get_matches();
 
-    let file_opt = matches.value_of("file").unwrap();
-    let mut file = File::open(file_opt).unwrap();
+    let defaults = Options::default();
+    let options = Options {
+        block_size: match size_arg(matches.value_of("block-size"), defaults.block_size, MAX_BLOCK_SIZE) {
+            Ok(x) => x,
+            Err(e) => clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit(),
+        },
+        window: match size_arg(matches.value_of("window"), defaults.window, usize::MAX) {
+            Ok(x) => x,
+            Err(e) => clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit(),
+        },
+        crc16:      matches.is_present("crc16"),
+        escape:     Escape {
+            controls:  matches.is_present("escape-all"),
+            telnet:    matches.is_present("telnet"),
+            eight_bit: matches.is_present("7bit"),
+        },
+        adaptive:   matches.is_present("adaptive"),
+        xon_xoff:   matches.is_present("xon-xoff"),
+        transport:  if matches.is_present("rle") { Transport::Rle }
+                    else if matches.is_present("lzw") { Transport::Lzw }
+                    else { Transport::None },
+        ..defaults
+    };
+
+    let conversion = if matches.is_present("resume") { Conversion::Resume } else { Conversion::None };
+    let management =
+        if matches.is_present("overwrite")   { Management::Clobber }
+        else if matches.is_present("append") { Management::Append }
+        else                                 { Management::None };
+
+    let mut errors = 0;
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    for arg in matches.values_of("file").unwrap() {
+        collect(arg, &mut entries, &mut visited, &mut errors);
+    }
+
+    let files_left = entries.len() as u32;
+    let mut bytes_left: u64 = entries.iter().map(|x| x.meta.len()).sum();
+
+    let files = entries.into_iter().enumerate().filter_map(|(i, entry)| {
+        let size = match u32::try_from(entry.meta.len()) {
+            Ok(x)  => x,
+            Err(_) => {
+                eprintln!("szm: {}: file too large", entry.path.display());
+                errors += 1;
+                return None;
+            },
+        };
+
+        let file = match File::open(&entry.path) {
+            Ok(x)  => tokio::fs::File::from_std(x),
+            Err(e) => {
+                eprintln!("szm: {}: {}", entry.path.display(), e);
+                errors += 1;
+                return None;
+            },
+        };
+
+        let info = FileInfo {
+            size:        Some(size),
+            mtime:       entry.meta.modified().ok()
+                              .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                              .map(|x| x.as_secs()),
+            mode:        mode(&entry.meta),
+            files_left:  Some(files_left - i as u32),
+            bytes_left:  Some(bytes_left),
+            conversion,
+            management,
+            ..FileInfo::new(&entry.name)
+        };
+        bytes_left -= entry.meta.len();
+
+        Some((info, file))
+    });
+
+    let trace: Box<dyn Write + Send> = match matches.value_of("trace") {
+        Some(path) => match File::create(path) {
+            Ok(x)  => Box::new(BufWriter::new(x)),
+            Err(e) => {
+                eprintln!("szm: {}: {}", path, e);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => Box::new(io::sink()),
+    };
+    let inout = zmodem::AsyncReadWrite::new(tokio::io::stdin(), tokio::io::stdout());
+    let inout = Capture::new(inout, trace, Format::JsonLines);
+    let result = zmodem::send::send_auto(inout, files, &options).await;
+
+    match result {
+        Err(e) => {
+            eprintln!("szm: {}", e);
+            ExitCode::from(EXIT_TRANSFER_FAILED)
+        },
+        Ok((_, stats)) => {
+            if stats.protocol != Protocol::Zmodem {
+                eprintln!("szm: receiver doesn't speak ZMODEM, sent by {:?}", stats.protocol);
+            }
+            if matches.is_present("verbose") {
+                eprintln!("szm: {} files, {} bytes sent, {} retransmission requests, subpackets {}..{} bytes",
+                          stats.files, stats.bytes, stats.rewinds, stats.min_block_size, stats.max_block_size);
+            }
+            if errors > 0 { ExitCode::from(EXIT_FILE_ERRORS) } else { ExitCode::SUCCESS }
+        },
+    }
+}
+
+fn size_arg(value: Option<&str>, default: usize, max: usize) -> Result<usize, String> {
+    match value {
+        Some(x) => x.parse().ok().filter(|&x| x > 0 && x <= max).ok_or(format!("invalid size: {}, 1 to {} allowed", x, max)),
+        None    => Ok(default),
+    }
+}
+
+/// Expands a command line argument into files to send.
+/// Glob patterns are expanded unless a file with that name exists,
+/// directories are sent recursively with pathnames relative to their parent.
+fn collect(arg: &str, entries: &mut Vec<Entry>, visited: &mut HashSet<PathBuf>, errors: &mut usize) {
+    let paths = if !Path::new(arg).exists() && arg.contains(['*', '?', '[']) {
+        match glob::glob(arg) {
+            Ok(x)  => x.filter_map(|x| x.ok()).collect::<Vec<_>>(),
+            Err(e) => {
+                eprintln!("szm: {}: {}", arg, e);
+                *errors += 1;
+                return;
+            },
+        }
+    }
+    else {
+        vec![PathBuf::from(arg)]
+    };
+
+    if paths.is_empty() {
+        eprintln!("szm: {}: no matches found", arg);
+        *errors += 1;
+    }
+
+    for path in paths {
+        // "." and ".." are sent by the name of the directory, "/" by the
+        // names of its children
+        let name = path.file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .or_else(|| fs::canonicalize(&path).ok()?.file_name().map(|x| x.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+
+        if let Err(e) = walk(path.clone(), name, entries, visited, errors) {
+            eprintln!("szm: {}: {}", path.display(), e);
+            *errors += 1;
+        }
+    }
+}
+
+/// Adds a file or a directory tree to `entries`. Errors of the entries
+/// of a directory are reported and counted without stopping the walk,
+/// directories already walked are skipped so symbolic link cycles end.
+fn walk(path: PathBuf, name: String, entries: &mut Vec<Entry>, visited: &mut HashSet<PathBuf>, errors: &mut usize) -> io::Result<()> {
+    let meta = fs::metadata(&path)?;
 
-    let filename = Path::new(file_opt).file_name().unwrap().clone();
-    let size = file.metadata().map(|x| x.len() as u32).ok();
+    if meta.is_dir() {
+        if !visited.insert(fs::canonicalize(&path)?) {
+            eprintln!("szm: {}: directory already sent, skipping", path.display());
+            return Ok(());
+        }
 
-    let inout = read_write::AsyncReadWrite::new(tokio::io::stdin, tokio::io::stdout);
+        let mut children = Vec::new();
+        for x in fs::read_dir(&path)? {
+            match x {
+                Ok(x)  => children.push(x.file_name()),
+                Err(e) => {
+                    eprintln!("szm: {}: {}", path.display(), e);
+                    *errors += 1;
+                },
+            }
+        }
+        children.sort();
+
+        for child in children {
+            let child_name = match name.as_str() {
+                "" => child.to_string_lossy().into_owned(),
+                _  => format!("{}/{}", name, child.to_string_lossy()),
+            };
+            let child_path = path.join(child);
+
+            if let Err(e) = walk(child_path.clone(), child_name, entries, visited, errors) {
+                eprintln!("szm: {}: {}", child_path.display(), e);
+                *errors += 1;
+            }
+        }
+    }
+    else if meta.is_file() {
+        entries.push(Entry { path, name, meta });
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(meta: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.mode())
+}
 
-    zmodem::send::send(inout, &mut file, filename.to_str().unwrap(), size).await.unwrap();
+#[cfg(not(unix))]
+fn mode(_meta: &Metadata) -> Option<u32> {
+    None
 }
@@ -0,0 +1,94 @@
+extern crate zmodem;
+
+extern crate clap;
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::process::ExitCode;
+use clap::{Arg, App};
+use zmodem::capture::{self, Direction, Format};
+use zmodem::dump::{self, EventKind};
+
+fn main() -> ExitCode {
+    let matches = App::new("Prints a timeline of captured ZMODEM traffic")
+        .arg(Arg::with_name("file")
+             .help("Capture to decode, standard input if omitted")
+             .required(false)
+             .index(1))
+        .arg(Arg::with_name("format")
+             .help("raw bytes of one direction, or a binary or JSON lines trace written by szm/rzm --trace")
+             .short("f")
+             .long("format")
+             .possible_values(&["raw", "binary", "json"])
+             .default_value("raw"))
+        .get_matches();
+
+    let input: Box<dyn Read> = match matches.value_of("file") {
+        Some(path) => match File::open(path) {
+            Ok(x)  => Box::new(x),
+            Err(e) => {
+                eprintln!("zmdump: {}: {}", path, e);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => Box::new(io::stdin()),
+    };
+    let mut input = BufReader::new(input);
+
+    let format = match matches.value_of("format") {
+        Some("binary") => Some(Format::Binary),
+        Some("json")   => Some(Format::JsonLines),
+        _              => None,
+    };
+
+    // (line, is a violation)
+    let lines = match format {
+        None => {
+            let mut data = Vec::new();
+            if let Err(e) = input.read_to_end(&mut data) {
+                eprintln!("zmdump: {}", e);
+                return ExitCode::FAILURE;
+            }
+
+            dump::decode(&data).into_iter()
+                .map(|x| (format!("{:>10} {}", x.offset, x.kind), is_violation(&x.kind)))
+                .collect::<Vec<_>>()
+        },
+        Some(format) => {
+            let records = match capture::read_trace(input, format) {
+                Ok(x)  => x,
+                Err(e) => {
+                    eprintln!("zmdump: {}", e);
+                    return ExitCode::FAILURE;
+                },
+            };
+
+            dump::decode_trace(&records).into_iter().map(|x| {
+                let dir = match x.direction {
+                    Direction::In  => "<",
+                    Direction::Out => ">",
+                };
+                (format!("{:>12.6} {} {}", x.time.as_secs_f64(), dir, x.event.kind), is_violation(&x.event.kind))
+            }).collect()
+        },
+    };
+
+    let mut out = io::stdout().lock();
+    for (line, _) in &lines {
+        // e.g. piped to head
+        if writeln!(out, "{}", line).is_err() {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let violations = lines.iter().filter(|(_, x)| *x).count();
+    if violations > 0 {
+        eprintln!("zmdump: {} protocol violations", violations);
+        return ExitCode::from(1);
+    }
+    ExitCode::SUCCESS
+}
+
+fn is_violation(kind: &EventKind) -> bool {
+    matches!(kind, EventKind::Violation(_) | EventKind::Truncated)
+}
@@ -0,0 +1,235 @@
+//! Capture of the bytes exchanged with the remote, e.g. to archive a
+//! session with a misbehaving device and analyse it later
+
+use hex::{FromHex, ToHex};
+use pin_project_lite::pin_project;
+use std::io::{self, BufRead, Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Encoding of a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Records of a direction byte (`<` in, `>` out), microseconds since
+    /// the start (u64 LE), data length (u32 LE) and the data
+    Binary,
+
+    /// One object per line: `{"us":1500,"dir":"in","data":"2a2a18"}`
+    JsonLines,
+}
+
+/// Direction of captured data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the remote
+    In,
+    /// Written to the remote
+    Out,
+}
+
+/// Data transferred at once in one direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the capture started
+    pub time:      Duration,
+    pub direction: Direction,
+    pub data:      Vec<u8>,
+}
+
+impl Record {
+    /// Appends the record to a trace
+    pub fn write<W: Write>(&self, w: &mut W, format: Format) -> io::Result<()> {
+        let us = self.time.as_micros() as u64;
+
+        match format {
+            Format::Binary => {
+                let dir = match self.direction {
+                    Direction::In  => b'<',
+                    Direction::Out => b'>',
+                };
+                w.write_all(&[dir])?;
+                w.write_all(&us.to_le_bytes())?;
+                w.write_all(&(self.data.len() as u32).to_le_bytes())?;
+                w.write_all(&self.data)
+            },
+            Format::JsonLines => {
+                let dir = match self.direction {
+                    Direction::In  => "in",
+                    Direction::Out => "out",
+                };
+                writeln!(w, r#"{{"us":{},"dir":"{}","data":"{}"}}"#, us, dir, self.data.to_hex())
+            },
+        }
+    }
+
+    /// Reads the next record of a trace, None at its end
+    pub fn read<R: BufRead>(r: &mut R, format: Format) -> io::Result<Option<Record>> {
+        match format {
+            Format::Binary => {
+                if r.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+
+                let mut head = [0; 13];
+                r.read_exact(&mut head)?;
+                let direction = match head[0] {
+                    b'<' => Direction::In,
+                    b'>' => Direction::Out,
+                    x    => return Err(invalid(format!("bad direction {:02X}", x))),
+                };
+                let us = u64::from_le_bytes(head[1..9].try_into().unwrap());
+                let len = u32::from_le_bytes(head[9..].try_into().unwrap());
+
+                // the length isn't trusted with an allocation, the data is
+                let mut data = Vec::new();
+                r.take(len as u64).read_to_end(&mut data)?;
+                if data.len() != len as usize {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              format!("{} of {} bytes of data", data.len(), len)));
+                }
+                Ok(Some(Record { time: Duration::from_micros(us), direction, data }))
+            },
+            Format::JsonLines => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if r.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if !line.trim().is_empty() {
+                        break;
+                    }
+                }
+
+                let us = field(&line, "us").and_then(|x| x.parse().ok())
+                    .ok_or_else(|| invalid(format!("bad time: {}", line.trim())))?;
+                let direction = match field(&line, "dir") {
+                    Some("in")  => Direction::In,
+                    Some("out") => Direction::Out,
+                    _           => return Err(invalid(format!("bad direction: {}", line.trim()))),
+                };
+                let data = field(&line, "data").and_then(|x| Vec::from_hex(x).ok())
+                    .ok_or_else(|| invalid(format!("bad data: {}", line.trim())))?;
+                Ok(Some(Record { time: Duration::from_micros(us), direction, data }))
+            },
+        }
+    }
+}
+
+/// Reads all records of a trace
+pub fn read_trace<R: BufRead>(mut r: R, format: Format) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    while let Some(x) = Record::read(&mut r, format)? {
+        records.push(x);
+    }
+    Ok(records)
+}
+
+/// Value of `"key":value` in a line of JSON written by `Record::write`
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!(r#""{}":"#, key))? + key.len() + 3;
+    let value = &line[start..];
+    let end = value.find([',', '}'])?;
+    Some(value[..end].trim().trim_matches('"'))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pin_project! {
+    /// Records every byte read from and written to `RW` in `trace`
+    /// regardless of the log level. Wrap the stream passed to `send` or
+    /// `recv` with it; failing to write the trace fails the transfer.
+    pub struct Capture<RW, T> {
+        #[pin]
+        inner:  RW,
+        trace:  T,
+        format: Format,
+        start:  Instant,
+    }
+}
+
+impl<RW, T: Write> Capture<RW, T> {
+    pub fn new(inner: RW, trace: T, format: Format) -> Self {
+        Self {
+            inner,
+            trace,
+            format,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn into_inner(self) -> (RW, T) {
+        (self.inner, self.trace)
+    }
+}
+
+/// Appends data to a trace
+fn record<T: Write>(trace: &mut T, format: Format, start: Instant, direction: Direction, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    Record { time: start.elapsed(), direction, data: data.to_vec() }.write(trace, format)
+}
+
+impl<RW: AsyncRead, T: Write> AsyncRead for Capture<RW, T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+
+        Poll::Ready(record(this.trace, *this.format, *this.start, Direction::In, &buf.filled()[filled..]))
+    }
+}
+
+impl<RW: AsyncWrite, T: Write> AsyncWrite for Capture<RW, T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = ready!(this.inner.poll_write(cx, buf))?;
+
+        record(this.trace, *this.format, *this.start, Direction::Out, &buf[..n])?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        ready!(this.inner.poll_flush(cx))?;
+        Poll::Ready(this.trace.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        ready!(this.inner.poll_shutdown(cx))?;
+        Poll::Ready(this.trace.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_capture() {
+        for format in [Format::Binary, Format::JsonLines] {
+            let (a, mut b) = tokio::io::duplex(64);
+            let mut c = Capture::new(a, Vec::new(), format);
+
+            c.write_all(b"**\x18B").await.unwrap();
+            b.write_all(b"OO").await.unwrap();
+            let mut buf = [0; 2];
+            c.read_exact(&mut buf).await.unwrap();
+
+            let (_, trace) = c.into_inner();
+            let records = read_trace(&trace[..], format).unwrap();
+            let records = records.iter().map(|x| (x.direction, &x.data[..])).collect::<Vec<_>>();
+            assert_eq!(records, [(Direction::Out, &b"**\x18B"[..]), (Direction::In, &b"OO"[..])]);
+        }
+
+        assert!(read_trace(&b"{\"us\":1,\"dir\":\"up\",\"data\":\"\"}\n"[..], Format::JsonLines).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 pub const ZPAD:   u8 = b'*';
 pub const ZLDE:   u8 = 0x18;
 pub const ZLDEE:  u8 = 0x58;
@@ -7,6 +9,8 @@ pub const ZBIN32: u8 = b'C'; // 0x43
 
 pub const ESC_FF: u8 = b'm';
 pub const ESC_7F: u8 = b'l';
+/// Sets the 8th bit of the next byte with XESC8, not part of ZMODEM
+pub const ESC_8:  u8 = b'n';
 
 /// Frame types
 pub const ZRQINIT: u8 =	0;	/* Request receive init */
@@ -36,4 +40,71 @@ pub const ZCRCG: u8 = b'i';	/* CRC next, frame continues nonstop */
 pub const ZCRCQ: u8 = b'j';	/* CRC next, frame continues, ZACK expected */
 pub const ZCRCW: u8 = b'k';	/* CRC next, ZACK expected, end of frame */
 
-pub const XON: u8 = 0x11;
+pub const XON:  u8 = 0x11;
+pub const XOFF: u8 = 0x13;
+
+/* Byte positions within header array */
+pub const ZF0: usize = 3;	/* First flags byte */
+pub const ZF1: usize = 2;
+pub const ZF2: usize = 1;
+pub const ZF3: usize = 0;
+pub const ZP0: usize = 0;	/* Low order 8 bits of position */
+pub const ZP1: usize = 1;
+
+/* Bit Masks for ZRINIT flags byte ZF0 */
+pub const CANFDX:  u8 = 0x01;	/* Rx can send and receive true FDX */
+pub const CANOVIO: u8 = 0x02;	/* Rx can receive data during disk I/O */
+pub const CANBRK:  u8 = 0x04;	/* Rx can send a break signal */
+pub const CANCRY:  u8 = 0x08;	/* Receiver can decrypt */
+pub const CANLZW:  u8 = 0x10;	/* Receiver can uncompress */
+pub const CANFC32: u8 = 0x20;	/* Receiver can use 32 bit Frame Check */
+pub const ESCCTL:  u8 = 0x40;	/* Receiver expects ctl chars to be escaped */
+pub const ESC8:    u8 = 0x80;	/* Receiver expects 8th bit to be escaped */
+
+/* Parameters for ZSINIT frame, ZF0 */
+pub const TESCCTL: u8 = 0x40;	/* Transmitter expects ctl chars to be escaped */
+pub const TESC8:   u8 = 0x80;	/* Transmitter expects 8th bit to be escaped */
+
+/* Parameters for ZFILE frame */
+/* Conversion options one of these in ZF0 */
+pub const ZCBIN:   u8 = 1;	/* Binary transfer - inhibit conversion */
+pub const ZCNL:    u8 = 2;	/* Convert NL to local end of line convention */
+pub const ZCRESUM: u8 = 3;	/* Resume interrupted file transfer */
+/* Management include options, one of these ored in ZF1 */
+pub const ZMSKNOLOC: u8 = 0x80;	/* Skip file if not present at rx */
+/* Management options, one of these ored in ZF1 */
+pub const ZMMASK:  u8 = 0x1f;	/* Mask for the choices below */
+pub const ZMNEWL:  u8 = 1;	/* Transfer if source newer or longer */
+pub const ZMCRC:   u8 = 2;	/* Transfer if different file CRC or length */
+pub const ZMAPND:  u8 = 3;	/* Append contents to existing file (if any) */
+pub const ZMCLOB:  u8 = 4;	/* Replace existing file */
+pub const ZMNEW:   u8 = 5;	/* Transfer if source newer */
+pub const ZMDIFF:  u8 = 6;	/* Transfer if dates or lengths different */
+pub const ZMPROT:  u8 = 7;	/* Protect destination file */
+pub const ZMCHNG:  u8 = 8;	/* Change filename if destination exists */
+/* Transport options, one of these in ZF2 */
+pub const ZTLZW:   u8 = 1;	/* Lempel-Ziv compression */
+pub const ZTRLE:   u8 = 3;	/* Run Length encoding */
+
+/* Extensions of this crate, in bits rzsz doesn't use */
+pub const CANXLZW: u8 = 0x80;	/* ZRINIT ZF1: receiver can uncompress our LZW */
+pub const ZXLZW:   u8 = 0x80;	/* ZFILE ZF3: data is compressed with our LZW */
+pub const XESC8:   u8 = 0x40;	/* ZRINIT ZF1: receiver expects the 8th bit as ZLDE ESC_8 */
+pub const TXESC8:  u8 = 0x40;	/* ZSINIT ZF1: transmitter expects the 8th bit as ZLDE ESC_8 */
+
+/* XMODEM/YMODEM */
+pub const SOH:    u8 = 0x01;	/* 128 byte block follows */
+pub const STX:    u8 = 0x02;	/* 1024 byte block follows */
+pub const EOT:    u8 = 0x04;	/* End of file */
+pub const ACK:    u8 = 0x06;
+pub const NAK:    u8 = 0x15;	/* Also requests checksum mode */
+pub const CAN:    u8 = 0x18;	/* Two in a row abort the transfer */
+pub const CPMEOF: u8 = 0x1A;	/* Padding of the last block */
+pub const WANTCRC: u8 = b'C';	/* Requests CRC-16 mode */
+pub const WANTG:  u8 = b'G';	/* Requests streaming mode (YMODEM-G) */
+
+/* Limits of all protocols */
+/// Retries of a frame, a block or a handshake before giving up
+pub const MAX_ERRORS: usize = 10;
+/// Wait for a reply or the rest of a frame or block
+pub const TIMEOUT: Duration = Duration::from_secs(10);
@@ -0,0 +1,109 @@
+use std::borrow::Cow;
+
+use crate::file_info::Conversion;
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+
+/// Conversion applied to received file data as requested by ZF0 of ZFILE
+#[derive(Debug, PartialEq)]
+pub enum Converter {
+    /// Data is written verbatim
+    Binary,
+
+    /// ZCNL: end of lines are converted to the local convention.
+    /// `cr` remembers a CR seen at the end of the previous subpacket.
+    Newline { cr: bool },
+}
+
+impl Converter {
+    pub fn new(conv: Conversion) -> Converter {
+        match conv {
+            Conversion::Newline => Converter::Newline { cr: false },
+            _                   => Converter::Binary,
+        }
+    }
+
+    /// Converts one verified subpacket. Only the output is affected,
+    /// offsets exchanged with the sender are always counted in wire bytes.
+    pub fn apply<'a>(&mut self, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Converter::Binary         => Cow::Borrowed(buf),
+            Converter::Newline { cr } => {
+                let mut out = Vec::with_capacity(buf.len() + buf.len() / 16);
+                for &x in buf {
+                    newline(cr, x, &mut out);
+                }
+                Cow::Owned(out)
+            },
+        }
+    }
+
+    /// Returns bytes held back at the end of the file
+    pub fn finish(&mut self) -> &'static [u8] {
+        match self {
+            Converter::Newline { cr } if *cr && !cfg!(windows) => {
+                *cr = false;
+                &[CR]
+            },
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn newline(cr: &mut bool, x: u8, out: &mut Vec<u8>) {
+    // CR LF -> LF, a lone CR is kept as is
+    if *cr && x != LF {
+        out.push(CR);
+    }
+    *cr = x == CR;
+    if !*cr {
+        out.push(x);
+    }
+}
+
+#[cfg(windows)]
+fn newline(cr: &mut bool, x: u8, out: &mut Vec<u8>) {
+    // LF -> CR LF unless already preceded by CR
+    if x == LF && !*cr {
+        out.push(CR);
+    }
+    *cr = x == CR;
+    out.push(x);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_newline() {
+        let mut c = Converter::new(Conversion::Newline);
+        assert_eq!(&c.apply(b"a\r\nb\rc\n")[..], b"a\nb\rc\n");
+
+        // CR LF split across subpackets
+        assert_eq!(&c.apply(b"x\r")[..], b"x");
+        assert_eq!(&c.apply(b"\ny\r")[..], b"\ny");
+        assert_eq!(c.finish(), b"\r");
+        assert_eq!(c.finish(), b"");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_newline() {
+        let mut c = Converter::new(Conversion::Newline);
+        assert_eq!(&c.apply(b"a\r\nb\nc")[..], b"a\r\nb\r\nc");
+        assert_eq!(&c.apply(b"x\r")[..], b"x\r");
+        assert_eq!(&c.apply(b"\n")[..], b"\n");
+        assert_eq!(c.finish(), b"");
+    }
+
+    #[test]
+    fn test_binary() {
+        let mut c = Converter::new(Conversion::Binary);
+        assert_eq!(&c.apply(b"a\r\n")[..], b"a\r\n");
+        assert_eq!(c.finish(), b"");
+    }
+}
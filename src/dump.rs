@@ -0,0 +1,286 @@
+//! Decoder of captured ZMODEM traffic into a timeline of frames,
+//! subpackets and protocol violations, see the `zmdump` tool
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::capture::{Direction, Record};
+use crate::consts::*;
+use crate::error::{ProtocolError, ZmodemError};
+use crate::file_info::FileInfo;
+use crate::frame::Frame;
+use crate::proto::{block_on, parse_header, recv_zlde_frame, terminator};
+
+/// Subpackets are reported as violations beyond the ZMODEM maximum
+const MAX_SUBPACKET: usize = 8192;
+
+/// Something found in a captured byte stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Position in the stream of one direction
+    pub offset: usize,
+    pub kind:   EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// Header with a valid CRC
+    Header {
+        encoding:   u8,
+        frame_type: u8,
+        flags:      [u8; 4],
+    },
+    /// Data subpacket with a valid CRC following ZDATA, ZFILE, ZSINIT,
+    /// ZCOMMAND or ZSTDERR, ended by ZCRCE, ZCRCG, ZCRCQ or ZCRCW
+    Subpacket {
+        len:  usize,
+        end:  u8,
+        /// Metadata carried by a ZFILE subpacket
+        file: Option<FileInfo>,
+    },
+    /// Bytes outside of frames, e.g. "OO" or XMODEM traffic
+    Garbage(Vec<u8>),
+    /// Damaged header or subpacket
+    Violation(ProtocolError),
+    /// The stream ends inside a header or subpacket
+    Truncated,
+}
+
+/// Event of a trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    /// Time of the record the event starts in
+    pub time:      Duration,
+    pub direction: Direction,
+    pub event:     Event,
+}
+
+/// Decodes bytes sent in one direction
+pub fn decode(data: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut r = data;
+    // header of the frame whose subpackets follow
+    let mut subpackets: Option<(u8, u8, [u8; 4])> = None;
+    // the terminator of the last subpacket is unknown
+    let mut damaged = false;
+
+    while !r.is_empty() {
+        let offset = data.len() - r.len();
+
+        if damaged && is_header(r) {
+            subpackets = None;
+        }
+
+        if let Some((encoding, frame_type, flags)) = subpackets {
+            let header = if encoding == ZBIN32 { ZBIN32 } else { ZBIN };
+            let mut buf = Vec::new();
+
+            match block_on(recv_zlde_frame(header, &mut r, &mut buf, MAX_SUBPACKET)) {
+                Ok(end) => {
+                    let file = (frame_type == ZFILE).then(|| FileInfo::from_zfile(flags, &buf));
+                    events.push(Event { offset, kind: EventKind::Subpacket { len: buf.len(), end, file } });
+                    if matches!(end, ZCRCE | ZCRCW) {
+                        subpackets = None;
+                    }
+                    damaged = false;
+                },
+                // the subpacket ended, the next one or a header follows
+                Err(ZmodemError::ProtocolError(e @ ProtocolError::BadCrc { .. })) => {
+                    events.push(Event { offset, kind: EventKind::Violation(e) });
+                    damaged = true;
+                },
+                Err(e) => {
+                    events.push(Event { offset, kind: violation(e) });
+                    subpackets = None;
+                },
+            }
+            continue;
+        }
+
+        let n = match r.iter().position(|&x| x == ZPAD) {
+            Some(0) if is_header(r) => 0,
+            // a lone ZPAD isn't a header
+            Some(0) => 1,
+            Some(n) => n,
+            None    => r.len(),
+        };
+        if n > 0 {
+            // join garbage split by ZPADs
+            match events.last_mut() {
+                Some(Event { offset: start, kind: EventKind::Garbage(x) }) if *start + x.len() == offset => {
+                    x.extend_from_slice(&r[..n]);
+                },
+                _ => events.push(Event { offset, kind: EventKind::Garbage(r[..n].to_vec()) }),
+            }
+            r = &r[n..];
+            continue;
+        }
+
+        let start = r.iter().position(|&x| x == ZLDE).unwrap() + 1;
+        r = &r[start..];
+
+        match block_on(parse_header(&mut r)) {
+            Ok(frame) => {
+                let (encoding, frame_type, flags) = (frame.get_header(), frame.get_frame_type(), frame.get_flags());
+                events.push(Event { offset, kind: EventKind::Header { encoding, frame_type, flags } });
+
+                if encoding == ZHEX {
+                    // CR LF and XON ending hex headers
+                    let n = r.iter().take(3).take_while(|&&x| matches!(x, 0x0d | 0x8d | 0x0a | 0x8a | XON)).count();
+                    r = &r[n..];
+                }
+                if matches!(frame_type, ZDATA | ZFILE | ZSINIT | ZCOMMAND | ZSTDERR) {
+                    subpackets = Some((encoding, frame_type, flags));
+                }
+            },
+            Err(e) => events.push(Event { offset, kind: violation(e) }),
+        }
+    }
+
+    events
+}
+
+/// Decodes both directions of a trace into one timeline
+pub fn decode_trace(records: &[Record]) -> Vec<TraceEvent> {
+    let mut events = Vec::new();
+
+    for direction in [Direction::In, Direction::Out] {
+        let mut stream = Vec::new();
+        // where the records start in the stream
+        let mut starts = Vec::new();
+
+        for x in records.iter().filter(|x| x.direction == direction) {
+            starts.push((stream.len(), x.time));
+            stream.extend_from_slice(&x.data);
+        }
+
+        for event in decode(&stream) {
+            let i = starts.partition_point(|&(offset, _)| offset <= event.offset) - 1;
+            events.push(TraceEvent { time: starts[i].1, direction, event });
+        }
+    }
+
+    events.sort_by_key(|x| x.time);
+    events
+}
+
+/// Checks whether data starts with ZPAD [ZPAD] ZLDE and a header type
+fn is_header(data: &[u8]) -> bool {
+    let data = if data.starts_with(&[ZPAD, ZPAD]) { &data[1..] } else { data };
+    matches!(data, [ZPAD, ZLDE, ZBIN | ZHEX | ZBIN32, ..])
+}
+
+fn violation(e: ZmodemError) -> EventKind {
+    match e {
+        ZmodemError::ProtocolError(e) => EventKind::Violation(e),
+        ZmodemError::IoError(_)       => EventKind::Truncated,
+    }
+}
+
+/// Names of the bits set in `byte`
+fn bits(byte: u8, names: &[(u8, &str)]) -> String {
+    let set = names.iter().filter(|(x, _)| byte & x != 0).map(|(_, name)| *name).collect::<Vec<_>>();
+    set.join("|")
+}
+
+/// Names of the bits set in ZF0 and in ZF1, where our extensions are
+fn flag_bits(flags: &[u8; 4], zf0: &[(u8, &str)], zf1: &[(u8, &str)]) -> String {
+    let set = [bits(flags[ZF0], zf0), bits(flags[ZF1], zf1)];
+    set.iter().filter(|x| !x.is_empty()).cloned().collect::<Vec<_>>().join("|")
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventKind::Header { encoding, frame_type, flags } => {
+                write!(f, "{} CRC ok", Frame::new(*encoding, *frame_type))?;
+
+                let offset = u32::from_le_bytes(*flags);
+                match *frame_type {
+                    ZRINIT => {
+                        let caps = [(CANFDX, "CANFDX"), (CANOVIO, "CANOVIO"), (CANBRK, "CANBRK"), (CANCRY, "CANCRY"),
+                                    (CANLZW, "CANLZW"), (CANFC32, "CANFC32"), (ESCCTL, "ESCCTL"), (ESC8, "ESC8")];
+                        let buffer = flags[ZP0] as u16 | (flags[ZP1] as u16) << 8;
+                        let caps = flag_bits(flags, &caps, &[(XESC8, "XESC8"), (CANXLZW, "CANXLZW")]);
+                        write!(f, ", buffer {}, capabilities {}", buffer, caps)
+                    },
+                    ZSINIT => {
+                        write!(f, ", flags {}", flag_bits(flags, &[(TESCCTL, "TESCCTL"), (TESC8, "TESC8")], &[(TXESC8, "TXESC8")]))
+                    },
+                    ZFILE => {
+                        write!(f, ", conversion {}, management {:#04x}, transport {}, extended {}",
+                               flags[ZF0], flags[ZF1], flags[ZF2], flags[ZF3])
+                    },
+                    ZRPOS | ZACK | ZDATA | ZEOF => write!(f, ", offset {}", offset),
+                    _ if offset != 0            => write!(f, ", flags {:02x?}", flags),
+                    _                           => Ok(()),
+                }
+            },
+            EventKind::Subpacket { len, end, file } => {
+                write!(f, "  subpacket {} bytes {} CRC ok", len, terminator(*end))?;
+
+                if let Some(x) = file {
+                    write!(f, ", file {:?}", x.name)?;
+                    if let Some(size) = x.size {
+                        write!(f, " size {}", size)?;
+                    }
+                    if let Some(mtime) = x.mtime {
+                        write!(f, " mtime {}", mtime)?;
+                    }
+                    if let Some(mode) = x.mode {
+                        write!(f, " mode {:o}", mode)?;
+                    }
+                    if let (Some(files), Some(bytes)) = (x.files_left, x.bytes_left) {
+                        write!(f, ", {} files {} bytes left", files, bytes)?;
+                    }
+                }
+                Ok(())
+            },
+            EventKind::Garbage(data) if data == b"OO" => write!(f, "OO (over and out)"),
+            EventKind::Garbage(data) => {
+                write!(f, "{} bytes outside frames: {:02x?}", data.len(), &data[..data.len().min(16)])?;
+                if data.len() > 16 {
+                    write!(f, "...")?;
+                }
+                Ok(())
+            },
+            EventKind::Violation(e) => write!(f, "! {}", e),
+            EventKind::Truncated    => write!(f, "! truncated"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut data = Frame::new(ZHEX, ZRQINIT).build();
+        data.extend_from_slice(&Frame::new(ZBIN32, ZDATA).count(100).build());
+        data.extend_from_slice(&[1, 2, 3, ZLDE, ZCRCE, 190, 214, 56, 242]);
+        data.extend_from_slice(&Frame::new(ZBIN, ZEOF).count(103).build());
+        data.extend_from_slice(b"OO");
+
+        let kinds = decode(&data).into_iter().map(|x| x.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [
+            EventKind::Header { encoding: ZHEX, frame_type: ZRQINIT, flags: [0; 4] },
+            EventKind::Header { encoding: ZBIN32, frame_type: ZDATA, flags: [100, 0, 0, 0] },
+            EventKind::Subpacket { len: 3, end: ZCRCE, file: None },
+            EventKind::Header { encoding: ZBIN, frame_type: ZEOF, flags: [103, 0, 0, 0] },
+            EventKind::Garbage(b"OO".to_vec()),
+        ]);
+
+        // damaged subpacket and a header cut short
+        let mut data = Frame::new(ZBIN32, ZDATA).build();
+        data.extend_from_slice(&[1, 2, 3, ZLDE, ZCRCE, 0, 0, 0, 0]);
+        data.extend_from_slice(&Frame::new(ZBIN, ZEOF).build()[..5]);
+
+        let kinds = decode(&data).into_iter().map(|x| x.kind).collect::<Vec<_>>();
+        assert!(matches!(kinds[..], [
+            EventKind::Header { .. },
+            EventKind::Violation(ProtocolError::BadCrc { .. }),
+            EventKind::Truncated,
+        ]));
+    }
+}
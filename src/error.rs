@@ -23,8 +23,52 @@ impl From<ProtocolError> for ZmodemError {
     }
 }
 
-#[derive(Debug, Error)]
+impl ZmodemError {
+    /// The input is damaged, the session may recover by requesting it again
+    pub(crate) fn is_damage(&self) -> bool {
+        matches!(self, ZmodemError::ProtocolError(
+            ProtocolError::BadCrc { .. } |
+            ProtocolError::BadHeaderEncoding |
+            ProtocolError::InvalidEscape(_) |
+            ProtocolError::SubpacketTooLong(_)))
+    }
+}
+
+/// Causes of a failed transfer. Damaged input is retransmitted by the
+/// sessions, so only its last occurrence is returned when they give up.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
 pub enum ProtocolError {
     #[error("Unexpected ZCRC byte: {0:02X}")]
     UnexpectedByteError(u8),
+    /// CRC of a header or subpacket doesn't match its data.
+    /// CRC-16 values are in the low 16 bits.
+    #[error("CRC mismatch: expected {expected:08X}, got {actual:08X}")]
+    BadCrc { expected: u32, actual: u32 },
+    /// Unknown header type or bad hex digits
+    #[error("Bad header encoding")]
+    BadHeaderEncoding,
+    #[error("Invalid escape sequence: ZDLE {0:02X}")]
+    InvalidEscape(u8),
+    #[error("Subpacket exceeds {0} bytes")]
+    SubpacketTooLong(usize),
+    /// A subpacket with a valid CRC doesn't uncompress
+    #[error("Bad compressed data")]
+    BadCompressedData,
+    /// The remote kept sending a frame the session can't handle in its state
+    #[error("Unexpected frame {frame:02X} in state {state}")]
+    UnexpectedFrame { state: String, frame: u8 },
+    /// The remote asked for data at an offset that can't be reached
+    #[error("Offset mismatch: expected {expected}, got {actual}")]
+    OffsetMismatch { expected: u32, actual: u32 },
+    #[error("Too many errors")]
+    TooManyErrors,
+    /// The remote sent a sequence of CANs
+    #[error("Transfer cancelled by the remote")]
+    Cancelled,
+    /// The remote sent ZABORT, ZFERR or ZCAN
+    #[error("Transfer aborted by the remote")]
+    RemoteAborted,
+    /// The remote didn't respond
+    #[error("Timeout")]
+    Timeout,
 }
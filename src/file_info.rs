@@ -0,0 +1,218 @@
+use std::str::from_utf8;
+
+use crate::consts::*;
+
+/// Conversion option requested by the sender (ZF0 of ZFILE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Conversion {
+    /// No option given
+    #[default]
+    None,
+    /// ZCBIN: binary transfer, inhibit conversion
+    Binary,
+    /// ZCNL: convert NL to local end of line convention
+    Newline,
+    /// ZCRESUM: resume interrupted file transfer
+    Resume,
+}
+
+impl Conversion {
+    fn from_zf0(zf0: u8) -> Conversion {
+        match zf0 {
+            ZCBIN   => Conversion::Binary,
+            ZCNL    => Conversion::Newline,
+            ZCRESUM => Conversion::Resume,
+            _       => Conversion::None,
+        }
+    }
+
+    fn to_zf0(self) -> u8 {
+        match self {
+            Conversion::None    => 0,
+            Conversion::Binary  => ZCBIN,
+            Conversion::Newline => ZCNL,
+            Conversion::Resume  => ZCRESUM,
+        }
+    }
+}
+
+/// File management option requested by the sender (ZF1 of ZFILE)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Management {
+    /// No option given
+    #[default]
+    None,
+    /// ZMNEWL: transfer if source newer or longer
+    NewerOrLonger,
+    /// ZMCRC: transfer if different file CRC or length
+    Crc,
+    /// ZMAPND: append contents to existing file (if any)
+    Append,
+    /// ZMCLOB: replace existing file
+    Clobber,
+    /// ZMNEW: transfer if source newer
+    Newer,
+    /// ZMDIFF: transfer if dates or lengths different
+    Different,
+    /// ZMPROT: protect destination file
+    Protect,
+    /// ZMCHNG: change filename if destination exists
+    Rename,
+}
+
+impl Management {
+    fn from_zf1(zf1: u8) -> Management {
+        match zf1 & ZMMASK {
+            ZMNEWL => Management::NewerOrLonger,
+            ZMCRC  => Management::Crc,
+            ZMAPND => Management::Append,
+            ZMCLOB => Management::Clobber,
+            ZMNEW  => Management::Newer,
+            ZMDIFF => Management::Different,
+            ZMPROT => Management::Protect,
+            ZMCHNG => Management::Rename,
+            _      => Management::None,
+        }
+    }
+
+    fn to_zf1(self) -> u8 {
+        match self {
+            Management::None          => 0,
+            Management::NewerOrLonger => ZMNEWL,
+            Management::Crc           => ZMCRC,
+            Management::Append        => ZMAPND,
+            Management::Clobber       => ZMCLOB,
+            Management::Newer         => ZMNEW,
+            Management::Different     => ZMDIFF,
+            Management::Protect       => ZMPROT,
+            Management::Rename        => ZMCHNG,
+        }
+    }
+}
+
+/// File description carried by ZFILE: header flags and the
+/// "name\0length mtime mode serial files-left bytes-left\0" subpacket
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FileInfo {
+    /// Pathname as sent, '/' separated
+    pub name: String,
+    /// Length in bytes
+    pub size: Option<u32>,
+    /// Modification time in seconds since the epoch
+    pub mtime: Option<u64>,
+    /// Unix file mode
+    pub mode: Option<u32>,
+    /// Files remaining in the batch, including this one
+    pub files_left: Option<u32>,
+    /// Bytes remaining in the batch, including this file
+    pub bytes_left: Option<u64>,
+    pub conversion: Conversion,
+    pub management: Management,
+    /// ZMSKNOLOC: skip the file if it isn't present at the receiver
+    pub skip_if_absent: bool,
+}
+
+impl FileInfo {
+    pub fn new(name: &str) -> FileInfo {
+        FileInfo {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Builds file info from ZFILE header flags and its subpacket
+    pub(crate) fn from_zfile(flags: [u8; 4], data: &[u8]) -> FileInfo {
+        let mut fields = data.split(|&x| x == 0);
+
+        let name = fields.next().unwrap_or_default();
+        let rest = fields.next().unwrap_or_default();
+        let mut rest = from_utf8(rest).unwrap_or_default().split_whitespace();
+
+        FileInfo {
+            name:           String::from_utf8_lossy(name).into_owned(),
+            size:           rest.next().and_then(|x| x.parse().ok()),
+            mtime:          rest.next().and_then(|x| u64::from_str_radix(x, 8).ok()),
+            mode:           rest.next().and_then(|x| u32::from_str_radix(x, 8).ok()),
+            files_left:     rest.nth(1).and_then(|x| x.parse().ok()), // skip serial number
+            bytes_left:     rest.next().and_then(|x| x.parse().ok()),
+            conversion:     Conversion::from_zf0(flags[ZF0]),
+            management:     Management::from_zf1(flags[ZF1]),
+            skip_if_absent: flags[ZF1] & ZMSKNOLOC != 0,
+        }
+    }
+
+    /// Builds ZFILE header flags and its subpacket
+    pub(crate) fn to_zfile(&self) -> ([u8; 4], Vec<u8>) {
+        let mut flags = [0; 4];
+        flags[ZF0] = self.conversion.to_zf0();
+        flags[ZF1] = self.management.to_zf1() | if self.skip_if_absent { ZMSKNOLOC } else { 0 };
+
+        // fields are positional, so they stop at the first unknown one
+        let fields = [
+            self.size.map(|x| x.to_string()),
+            self.mtime.map(|x| format!("{:o}", x)),
+            self.mode.map(|x| format!("{:o}", x)),
+            self.files_left.map(|_| "0".to_string()), // serial number
+            self.files_left.map(|x| x.to_string()),
+            self.bytes_left.map(|x| x.to_string()),
+        ];
+        let fields = fields.into_iter().map_while(|x| x).collect::<Vec<_>>();
+
+        let mut data = self.name.clone().into_bytes();
+        data.push(0);
+        data.extend_from_slice(fields.join(" ").as_bytes());
+        data.push(0);
+
+        (flags, data)
+    }
+}
+
+#[test]
+fn test_from_zfile() {
+    let info = FileInfo::from_zfile([0, 0, 0, 0], b"test\0");
+    assert_eq!(info, FileInfo::new("test"));
+
+    let info = FileInfo::from_zfile([0, 0, 0, 0], b"test\0 123\0");
+    assert_eq!(info.size, Some(123));
+    assert_eq!(info.mtime, None);
+
+    let info = FileInfo::from_zfile(
+        [0, 0, ZMSKNOLOC | ZMCLOB, ZCNL],
+        b"dir/file.txt\x0011 14201636372 100644 0 3 2048\0\0\0");
+    assert_eq!(info, FileInfo {
+        name:           "dir/file.txt".to_string(),
+        size:           Some(11),
+        mtime:          Some(0o14201636372),
+        mode:           Some(0o100644),
+        files_left:     Some(3),
+        bytes_left:     Some(2048),
+        conversion:     Conversion::Newline,
+        management:     Management::Clobber,
+        skip_if_absent: true,
+    });
+}
+
+#[test]
+fn test_to_zfile() {
+    assert_eq!(FileInfo::new("test").to_zfile(), ([0; 4], b"test\0\0".to_vec()));
+
+    let info = FileInfo {
+        name:       "test".to_string(),
+        size:       Some(123),
+        mode:       Some(0o644),
+        conversion: Conversion::Resume,
+        management: Management::Append,
+        ..Default::default()
+    };
+    assert_eq!(info.to_zfile(), ([0, 0, ZMAPND, ZCRESUM], b"test\x00123\0".to_vec()));
+
+    let info = FileInfo {
+        mtime:      Some(0o14201636372),
+        files_left: Some(2),
+        bytes_left: Some(1000),
+        ..info
+    };
+    let (flags, data) = info.to_zfile();
+    assert_eq!(data, b"test\x00123 14201636372 644 0 2 1000\0".to_vec());
+    assert_eq!(FileInfo::from_zfile(flags, &data), info);
+}
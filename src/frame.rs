@@ -10,6 +10,7 @@ pub struct Frame {
     header: u8,
     ftype: u8,
     flags: [u8; 4],
+    escape: proto::Escape,
 }
 
 impl Frame {
@@ -18,6 +19,7 @@ impl Frame {
             header,
             ftype,
             flags: [0; 4],
+            escape: proto::Escape::MINIMAL,
         }
     }
 
@@ -26,9 +28,15 @@ impl Frame {
         self
     }
 
-    pub fn count<'b>(&'b mut self, count: u32) -> &'b mut Frame {
+    /// Bytes to escape in binary headers
+    pub fn escape(&mut self, escape: proto::Escape) -> &mut Frame {
+        self.escape = escape;
+        self
+    }
+
+    pub fn count(&mut self, count: u32) -> &mut Frame {
         self.flags = [
-               (count >>  0) as u8,
+                count        as u8,
                (count >>  8) as u8,
                (count >> 16) as u8,
                (count >> 24) as u8,
@@ -61,12 +69,12 @@ impl Frame {
 
         if self.header == ZHEX {
             let hex = out.drain(4..).collect::<Vec<u8>>().to_hex();
-            out.extend_from_slice(&hex.as_bytes());
+            out.extend_from_slice(hex.as_bytes());
         }
 
         let tmp = out.drain(3..).collect::<Vec<_>>();
         let mut tmp2 = Vec::new();
-        proto::escape_buf(&tmp, &mut tmp2);
+        proto::escape_buf(&tmp, &mut tmp2, self.escape);
         out.extend_from_slice(&tmp2);
 
         if self.header == ZHEX {
@@ -80,6 +88,10 @@ impl Frame {
         out
     }
 
+    pub fn get_flags(&self) -> [u8; 4] {
+        self.flags
+    }
+
     pub fn get_frame_type(&self) -> u8 {
         self.ftype
     }
@@ -87,30 +99,20 @@ impl Frame {
     pub fn get_header(&self) -> u8 {
         self.header
     }
-}
-
-fn get_crc(header: u8, buf: &[u8]) -> Vec<u8> {
-    let offset = match header {
-        ZHEX => 4,
-        _    => 3,
-    };
-
-    match header {
-        ZBIN32 => crc::get_crc32(&buf[offset..], None).to_vec(),
-        _      => crc::get_crc16(&buf[offset..], None).to_vec(),
-    }
-}
 
-impl fmt::Display for Frame {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let hdr = match self.header {
+    /// Name of the encoding, e.g. "ZBIN32"
+    pub fn encoding_name(&self) -> &'static str {
+        match self.header {
             ZHEX   => "ZHEX",
             ZBIN   => "ZBIN",
             ZBIN32 => "ZBIN32",
             _      => "???",
-        };
+        }
+    }
 
-        let ft = match self.ftype {
+    /// Name of the frame type, e.g. "ZRPOS"
+    pub fn type_name(&self) -> &'static str {
+        match self.ftype {
             ZRQINIT    => "ZRQINIT",
             ZRINIT     => "ZRINIT",
             ZSINIT     => "ZSINIT",
@@ -132,9 +134,25 @@ impl fmt::Display for Frame {
             ZCOMMAND   => "ZCOMMAND",
             ZSTDERR    => "ZSTDERR",
             _          => "???",
-        };
+        }
+    }
+}
 
-        write!(f, "{}({})", hdr, ft)
+fn get_crc(header: u8, buf: &[u8]) -> Vec<u8> {
+    let offset = match header {
+        ZHEX => 4,
+        _    => 3,
+    };
+
+    match header {
+        ZBIN32 => crc::get_crc32(&buf[offset..], None).to_vec(),
+        _      => crc::get_crc16(&buf[offset..], None).to_vec(),
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.encoding_name(), self.type_name())
     }
 }
 
@@ -0,0 +1,45 @@
+//! Entry points of the fuzz targets in `fuzz/`, built by cargo-fuzz with `--cfg fuzzing`
+
+use crate::consts::*;
+use crate::proto::{block_on, parse_header, recv_zlde_frame};
+use crate::transport::Transport;
+
+/// Longest subpacket decoded, enough to hit the limit with small inputs
+const MAX_SUBPACKET: usize = 1024;
+
+/// Decodes a header following ZPAD ZPAD ZLDE
+pub fn decode_header(data: &[u8]) {
+    let _ = block_on(parse_header(data));
+}
+
+/// Decodes a data subpacket, the first byte selects ZBIN or ZBIN32
+pub fn decode_subpacket(data: &[u8]) {
+    let (header, mut data) = match data.split_first() {
+        Some((x, rest)) if x & 1 == 0 => (ZBIN, rest),
+        Some((_, rest))               => (ZBIN32, rest),
+        None                          => return,
+    };
+
+    let mut buf = Vec::new();
+    if block_on(recv_zlde_frame(header, &mut data, &mut buf, MAX_SUBPACKET)).is_ok() {
+        assert!(buf.len() <= MAX_SUBPACKET);
+    }
+}
+
+/// Uncompresses a subpacket and compresses data, which has to come back
+/// the same. The first byte selects ZTRLE or LZW.
+pub fn transport(data: &[u8]) {
+    let (transport, data) = match data.split_first() {
+        Some((x, rest)) if x & 1 == 0 => (Transport::Rle, rest),
+        Some((_, rest))               => (Transport::Lzw, rest),
+        None                          => return,
+    };
+
+    if let Ok(x) = transport.uncompress(data, MAX_SUBPACKET) {
+        assert!(x.len() <= MAX_SUBPACKET);
+    }
+
+    let packed = transport.compress(data);
+    assert!(packed.len() <= transport.max_len(data.len()));
+    assert_eq!(transport.uncompress(&packed, data.len()).unwrap(), data);
+}
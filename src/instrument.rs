@@ -0,0 +1,148 @@
+//! Session spans and structured events of the `tracing` feature, session
+//! metrics of the `metrics` feature. Without the features they compile to
+//! nothing and only the `log` output remains.
+
+use std::future::Future;
+
+use crate::error::Result;
+#[cfg(feature = "metrics")]
+use crate::error::{ProtocolError, ZmodemError};
+
+#[cfg(feature = "metrics")]
+use metrics::Label;
+#[cfg(feature = "metrics")]
+use std::cell::RefCell;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+/// Emits a `tracing` event with fields, e.g.
+/// `event!(debug, frame = "ZRPOS", count = 1024, "header sent")`
+macro_rules! event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    };
+}
+
+#[cfg(feature = "metrics")]
+thread_local! {
+    /// Labels of the session being polled on this thread
+    static LABELS: RefCell<Option<Arc<Vec<Label>>>> = const { RefCell::new(None) };
+}
+
+/// Runs a session in a span naming the remote and the direction of the
+/// transfer, the name of the current file is added to it as it's known.
+/// Metrics recorded while the session runs are labelled the same way.
+#[cfg_attr(not(any(feature = "tracing", feature = "metrics")), allow(unused_variables))]
+pub(crate) async fn session<F, T>(f: F, direction: &'static str, peer: &str) -> Result<T>
+    where F: Future<Output = Result<T>>
+{
+    #[cfg(feature = "tracing")]
+    let f = tracing::Instrument::instrument(f, tracing::info_span!("zmodem", direction, peer, file = tracing::field::Empty));
+
+    #[cfg(feature = "metrics")]
+    let labels = Arc::new(vec![Label::new("direction", direction), Label::new("peer", peer.to_string())]);
+    #[cfg(feature = "metrics")]
+    let (start, f) = (std::time::Instant::now(), Metered { inner: f, labels: labels.clone() });
+
+    let result = f.await;
+
+    #[cfg(feature = "metrics")]
+    {
+        let outcome = match &result {
+            Ok(_)  => "ok",
+            Err(e) => outcome(e),
+        };
+        let mut labels = labels.to_vec();
+        metrics::histogram!("zmodem_session_duration_seconds", labels.iter()).record(start.elapsed());
+        labels.push(Label::new("outcome", outcome));
+        metrics::counter!("zmodem_sessions_total", labels.iter()).increment(1);
+    }
+
+    result
+}
+
+/// Records the file being transferred in the session span
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn file(name: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("file", name);
+}
+
+/// Adds to a counter of the session being polled
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn count(name: &'static str, n: u64) {
+    #[cfg(feature = "metrics")]
+    with_labels(|labels| metrics::counter!(name, labels.iter()).increment(n));
+}
+
+/// Counts a header sent or received by type
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn count_frame(name: &'static str, frame_type: &'static str) {
+    #[cfg(feature = "metrics")]
+    with_labels(|labels| {
+        let mut labels = labels.to_vec();
+        labels.push(Label::new("frame", frame_type));
+        metrics::counter!(name, labels.iter()).increment(1);
+    });
+}
+
+/// Records a value in a histogram of the session being polled
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record(name: &'static str, value: f64) {
+    #[cfg(feature = "metrics")]
+    with_labels(|labels| metrics::histogram!(name, labels.iter()).record(value));
+}
+
+/// Label of a failed session
+#[cfg(feature = "metrics")]
+fn outcome(e: &ZmodemError) -> &'static str {
+    match e {
+        ZmodemError::IoError(_) => "io_error",
+        ZmodemError::ProtocolError(e) => match e {
+            ProtocolError::UnexpectedFrame { .. } => "unexpected_frame",
+            ProtocolError::OffsetMismatch { .. }  => "offset_mismatch",
+            ProtocolError::TooManyErrors          => "too_many_errors",
+            ProtocolError::Cancelled              => "cancelled",
+            ProtocolError::RemoteAborted          => "remote_aborted",
+            ProtocolError::Timeout                => "timeout",
+            ProtocolError::BadCompressedData      => "bad_compressed_data",
+            _                                     => "damaged_input",
+        },
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn with_labels<F: FnOnce(&[Label])>(f: F) {
+    LABELS.with(|x| {
+        if let Some(labels) = &*x.borrow() {
+            f(labels);
+        }
+    });
+}
+
+#[cfg(feature = "metrics")]
+pin_project_lite::pin_project! {
+    /// Makes the labels of a session current while it's polled, the way
+    /// spans are entered
+    struct Metered<F> {
+        #[pin]
+        inner:  F,
+        labels: Arc<Vec<Label>>,
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<F: Future> Future for Metered<F> {
+    type Output = F::Output;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<F::Output> {
+        let this = self.project();
+
+        // restore the labels of an enclosing session, if any
+        let outer = LABELS.with(|x| x.replace(Some(this.labels.clone())));
+        let poll = this.inner.poll(cx);
+        LABELS.with(|x| x.replace(outer));
+        poll
+    }
+}
@@ -1,12 +1,38 @@
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+mod instrument;
 mod error;
 mod consts;
+mod convert;
+mod file_info;
 mod frame;
 mod crc;
 mod proto;
 mod rwlog;
+mod read_write;
+mod rewind;
+mod transport;
 
+pub mod capture;
+pub mod dump;
 pub mod recv;
 pub mod send;
+pub mod xmodem;
+pub mod ymodem;
+
+pub use error::{ProtocolError, Result, ZmodemError};
+pub use file_info::{Conversion, FileInfo, Management};
+pub use proto::Escape;
+pub use read_write::AsyncReadWrite;
+pub use transport::Transport;
+
+#[cfg(feature = "process")]
+pub mod process;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(fuzzing)]
+pub mod fuzz;
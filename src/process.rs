@@ -0,0 +1,91 @@
+use std::io;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::task::{Context, Poll};
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::AsyncReadWrite;
+
+pin_project! {
+    /// Duplex stream over stdin/stdout of a spawned program,
+    /// e.g. lrzsz's `sz`/`rz`, a serial tool or `ssh host rz`
+    pub struct Process {
+        child: Child,
+        #[pin]
+        io: AsyncReadWrite<ChildStdout, ChildStdin>,
+    }
+}
+
+/// Spawns `cmd` with piped stdin and stdout. Stderr is left as configured in `cmd`.
+pub fn spawn(cmd: &mut Command) -> io::Result<Process> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    Ok(Process { child, io: AsyncReadWrite::new(stdout, stdin) })
+}
+
+impl Process {
+    /// OS identifier of the child, `None` if it has already been polled to completion
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Kills the child
+    pub async fn kill(&mut self) -> io::Result<()> {
+        self.child.kill().await
+    }
+
+    /// Closes the pipes and waits for the child to exit
+    pub async fn wait(self) -> io::Result<ExitStatus> {
+        let Process { mut child, io } = self;
+        drop(io);
+        child.wait().await
+    }
+}
+
+impl AsyncRead for Process {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.project().io.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Process {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.project().io.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().io.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().io.poll_shutdown(cx)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spawn_cat() {
+        let mut p = spawn(&mut Command::new("cat")).unwrap();
+
+        p.write_all(b"ZMODEM").await.unwrap();
+        p.flush().await.unwrap();
+
+        let mut buf = [0; 6];
+        p.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ZMODEM");
+
+        assert!(p.wait().await.unwrap().success());
+    }
+}
@@ -1,11 +1,93 @@
 use hex::*;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
 use log::LogLevel::{Debug};
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::consts::*;
+use crate::convert::Converter;
 use crate::frame::*;
 use crate::crc::*;
-use crate::error::{Result, ProtocolError};
+use crate::error::{Result, ProtocolError, ZmodemError};
+use crate::file_info::FileInfo;
+use crate::instrument;
+use crate::transport::Transport;
+
+/// Encoding of outgoing binary headers and data subpackets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Encoding {
+    /// ZBIN (CRC-16) or ZBIN32 (CRC-32)
+    pub header: u8,
+
+    pub escape: Escape,
+
+    /// Compression of file data, announced in ZFILE
+    pub transport: Transport,
+}
+
+/// Bytes escaped with ZLDE in outgoing headers and data subpackets besides
+/// ZLDE, DLE, XON, XOFF, 0x7F and 0xFF, which always are. Received data is
+/// unescaped the same way whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Escape {
+    /// ESCCTL: all control characters
+    pub controls: bool,
+
+    /// CR after '@', which telnet servers and modems may take for the end
+    /// of a command. Local to the sender: ZMODEM has no flag asking the
+    /// remote for it and it's unescaped as any other byte.
+    pub telnet: bool,
+
+    /// Bytes with the 8th bit set, for lines passing 7 bits, sent as ZLDE
+    /// ESC_8 and the low 7 bits escaped as any other byte. An extension of
+    /// this crate asked for with XESC8 in ZF1 of ZRINIT and TXESC8 of
+    /// ZSINIT: ESC8 and TESC8 leave the encoding open and are ignored.
+    pub eight_bit: bool,
+}
+
+impl Escape {
+    /// Only the bytes that are always escaped
+    pub const MINIMAL: Escape = Escape { controls: false, telnet: false, eight_bit: false };
+    pub const CONTROLS: Escape = Escape { controls: true, ..Escape::MINIMAL };
+    pub const TELNET: Escape = Escape { telnet: true, ..Escape::MINIMAL };
+    pub const SEVEN_BIT: Escape = Escape { eight_bit: true, ..Escape::MINIMAL };
+
+    /// Reads ESCCTL and XESC8 of ZRINIT or TESCCTL and TXESC8 of ZSINIT,
+    /// which are the same bits of ZF0 and ZF1
+    pub fn from_flags(flags: &[u8; 4]) -> Escape {
+        Escape {
+            controls:  flags[ZF0] & ESCCTL != 0,
+            telnet:    false,
+            eight_bit: flags[ZF1] & XESC8 != 0,
+        }
+    }
+
+    /// Sets the flags asking the remote to escape the same way, telnet has none
+    pub fn to_flags(self, flags: &mut [u8; 4]) {
+        if self.controls {
+            flags[ZF0] |= ESCCTL;
+        }
+        if self.eight_bit {
+            flags[ZF1] |= XESC8;
+        }
+    }
+
+    /// There are flags asking the remote for it
+    pub(crate) fn is_negotiated(self) -> bool {
+        self.controls || self.eight_bit
+    }
+
+    /// Escapes everything either policy does
+    pub fn union(self, other: Escape) -> Escape {
+        Escape {
+            controls:  self.controls || other.controls,
+            telnet:    self.telnet || other.telnet,
+            eight_bit: self.eight_bit || other.eight_bit,
+        }
+    }
+}
 
 /// Looking for sequence: ZPAD [ZPAD] ZLDE
 /// Returns true if found otherwise false
@@ -18,11 +100,11 @@ pub async fn find_zpad<R>(r: &mut R) -> Result<bool>
     }
 
     // get next byte
-    let mut b = read_byte(r).await?;
+    let mut b = read_byte_skip_flow(r).await?;
 
     // skip second ZPAD
     if b == ZPAD {
-        b = read_byte(r).await?;
+        b = read_byte_skip_flow(r).await?;
     }
 
     // expect ZLDE
@@ -33,17 +115,22 @@ pub async fn find_zpad<R>(r: &mut R) -> Result<bool>
     Ok(true)
 }
 
-pub async fn parse_header<'a, R>(mut r: R) -> Result<Option<Frame>>
+/// Skips input up to a header and reads it, None if it's damaged
+pub async fn recv_header<R>(r: &mut R) -> Result<Option<Frame>>
     where R: AsyncRead + Unpin {
 
-    let header = read_byte(&mut r).await?;
+    while !find_zpad(r).await? {}
+    or_damaged(parse_header(r).await)
+}
+
+pub async fn parse_header<R>(mut r: R) -> Result<Frame>
+    where R: AsyncRead + Unpin {
+
+    let header = read_byte_skip_flow(&mut r).await?;
 
     match header {
        ZBIN32 | ZBIN | ZHEX => (),
-       _ => {
-           error!("unexpected header byte!");
-           return Ok(None)
-       },
+       _ => return Err(ProtocolError::BadHeaderEncoding.into()),
     };
 
     let len = 1 + 4; // frame type + flags
@@ -54,29 +141,21 @@ pub async fn parse_header<'a, R>(mut r: R) -> Result<Option<Frame>>
     read_exact_unescaped(r, &mut v).await?;
 
     if header == ZHEX {
-        v = match FromHex::from_hex(&v) {
-            Ok(x) => x,
-            _     => {
-                error!("from_hex error");
-                return Ok(None);
-            },
-        }
+        v = FromHex::from_hex(&v).map_err(|_| ProtocolError::BadHeaderEncoding)?;
     }
 
-    let crc1 = v[5..].to_vec();
-    let crc2 = match header {
+    let crc = match header {
         ZBIN32 => get_crc32(&v[..5], None).to_vec(),
         _      => get_crc16(&v[..5], None).to_vec(),
     };
-
-    if crc1 != crc2 {
-        error!("crc mismatch: {:?} != {:?}", crc1, crc2);
-        return Ok(None);
-    }
+    check_crc(&crc, &v[5..])?;
 
     let mut frame = Frame::new(header, v[0]);
     frame.flags(&[v[1], v[2], v[3], v[4]]);
 
+    event!(debug, frame = frame.type_name(), encoding = frame.encoding_name(), flags = ?frame.get_flags(), count = frame.get_count(), "header received");
+    instrument::count_frame("zmodem_frames_received_total", frame.type_name());
+
     if log_enabled!(Debug) {
         debug!("Got frame: {}", frame);
         match frame.get_frame_type() {
@@ -85,7 +164,23 @@ pub async fn parse_header<'a, R>(mut r: R) -> Result<Option<Frame>>
         }
     }
 
-    Ok(Some(frame))
+    Ok(frame)
+}
+
+/// Compares CRC bytes computed over data with the received ones
+fn check_crc(expected: &[u8], actual: &[u8]) -> Result<()> {
+    if expected == actual {
+        return Ok(());
+    }
+
+    // CRC-16 is sent in big endian, CRC-32 in little endian
+    let value = |x: &[u8]| match *x {
+        [a, b]       => u16::from_be_bytes([a, b]) as u32,
+        [a, b, c, d] => u32::from_le_bytes([a, b, c, d]),
+        _            => 0,
+    };
+
+    Err(ProtocolError::BadCrc { expected: value(expected), actual: value(actual) }.into())
 }
 
 /// Read out up to len bytes and remove escaped ones
@@ -93,8 +188,11 @@ async fn read_exact_unescaped<R>(mut r: R, buf: &mut [u8]) -> Result<()>
     where R: AsyncRead + Unpin {
 
     for x in buf {
-        *x = match read_byte(&mut r).await? {
-            ZLDE => unescape(read_byte(&mut r).await?),
+        *x = match read_byte_skip_flow(&mut r).await? {
+            ZLDE => {
+                let b = read_byte_skip_flow(&mut r).await?;
+                unescape_next(&mut r, b).await?
+            },
             y    => y,
         };
     }
@@ -104,41 +202,86 @@ async fn read_exact_unescaped<R>(mut r: R, buf: &mut [u8]) -> Result<()>
 
 /// Receives sequence: <escaped data> ZLDE ZCRC* <CRC bytes>
 /// Unescapes sequencies such as 'ZLDE <escaped byte>'
-/// If Ok returns <unescaped data> in buf and ZCRC* byte as return value
-pub async fn recv_zlde_frame<R>(header: u8, r: &mut R, buf: &mut Vec<u8>) -> Result<Option<u8>>
+/// If Ok returns <unescaped data> in buf and ZCRC* byte as return value.
+/// Data longer than `max_len` is rejected before it's all read.
+pub async fn recv_zlde_frame<R>(header: u8, r: &mut R, buf: &mut Vec<u8>, max_len: usize) -> Result<u8>
     where R: AsyncBufRead + Unpin {
 
-    loop {
-        r.read_until(ZLDE, buf).await?;
-        let b = read_byte(r).await?;
+    let zcrc = loop {
+        let available = r.fill_buf().await?;
+        if available.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
-        if !is_escaped(b) {
-            *buf.last_mut().unwrap() = b; // replace ZLDE by ZCRC* byte
-            break;
+        let (n, found) = match available.iter().position(|&x| x == ZLDE) {
+            Some(n) => (n, true),
+            None    => (available.len(), false),
+        };
+        buf.extend(available[..n].iter().filter(|&&x| !is_flow_control(x)));
+        r.consume(n + found as usize);
+
+        // before the terminator, which may be in the same chunk
+        if buf.len() > max_len {
+            return Err(ProtocolError::SubpacketTooLong(max_len).into());
         }
 
-        *buf.last_mut().unwrap() = unescape(b);
-    }
+        if found {
+            match read_byte_skip_flow(r).await? {
+                b @ (ZCRCE | ZCRCG | ZCRCQ | ZCRCW) => break b,
+                b                                   => buf.push(unescape_next(r, b).await?),
+            }
+        }
+    };
 
     let crc_len = if header == ZBIN32 { 4 } else { 2 };
-    let mut crc1 = vec![0; crc_len];
+    let mut crc = vec![0; crc_len];
 
-    read_exact_unescaped(r, &mut crc1).await?;
+    read_exact_unescaped(r, &mut crc).await?;
 
-    let crc2 = match header {
-        ZBIN32 => get_crc32(buf, None).to_vec(),
-        _      => get_crc16(buf, None).to_vec(),
-    };
+    match header {
+        ZBIN32 => check_crc(&get_crc32(buf, Some(zcrc)), &crc)?,
+        _      => check_crc(&get_crc16(buf, Some(zcrc)), &crc)?,
+    }
+
+    event!(trace, end = terminator(zcrc), len = buf.len(), "subpacket received");
+    Ok(zcrc)
+}
 
-    if crc1 != crc2 {
-        error!("crc mismatch: {:?} != {:?}", crc1, crc2);
-        return Ok(None);
+/// Name of a subpacket terminator
+pub fn terminator(zcrc: u8) -> &'static str {
+    match zcrc {
+        ZCRCE => "ZCRCE",
+        ZCRCG => "ZCRCG",
+        ZCRCQ => "ZCRCQ",
+        ZCRCW => "ZCRCW",
+        _     => "???",
     }
+}
 
-    Ok(buf.pop()) // pop ZCRC* byte
+/// Turns errors caused by damaged input into None, the sessions recover
+/// from them by requesting the data again
+pub fn or_damaged<T>(r: Result<T>) -> Result<Option<T>> {
+    match r {
+        Ok(x)                   => Ok(Some(x)),
+        Err(e) if e.is_damage() => {
+            error!("{}", e);
+            event!(warn, error = %e, "damaged input");
+            if let ZmodemError::ProtocolError(ProtocolError::BadCrc { .. }) = e {
+                instrument::count("zmodem_crc_errors_total", 1);
+            }
+            Ok(None)
+        },
+        Err(e)                  => Err(e),
+    }
 }
 
-pub async fn recv_data<RW, OUT>(header: u8, count: &mut u32, rw: &mut RW, out: &mut OUT) -> Result<bool>
+/// Receives data subpackets up to ZCRCE or ZCRCW. `count` advances by the
+/// uncompressed length, `max_len` limits the uncompressed length of a
+/// subpacket.
+/// Returns false if a subpacket is damaged.
+#[allow(clippy::too_many_arguments)]
+pub async fn recv_data<RW, OUT>(header: u8, count: &mut u32, rw: &mut RW, out: &mut OUT, conv: &mut Converter,
+                                transport: Transport, max_len: usize) -> Result<bool>
     where RW: AsyncWrite + AsyncBufRead + Unpin,
          OUT: AsyncWrite + Unpin {
 
@@ -147,13 +290,17 @@ pub async fn recv_data<RW, OUT>(header: u8, count: &mut u32, rw: &mut RW, out: &
     loop {
         buf.clear();
 
-        let zcrc = match recv_zlde_frame(header, rw, &mut buf).await? {
+        let zcrc = match or_damaged(recv_zlde_frame(header, rw, &mut buf, transport.max_len(max_len)).await)? {
             Some(x) => x,
             None    => return Ok(false),
         };
 
-        out.write_all(&buf).await?;
-        *count += buf.len() as u32;
+        let data = match or_damaged(transport.uncompress(&buf, max_len))? {
+            Some(x) => x,
+            None    => return Ok(false),
+        };
+        out.write_all(&conv.apply(&data)).await?;
+        *count += data.len() as u32;
 
         match zcrc {
             ZCRCW => {
@@ -180,35 +327,140 @@ pub async fn recv_data<RW, OUT>(header: u8, count: &mut u32, rw: &mut RW, out: &
 }
 
 /// Converts escaped byte to unescaped one
-fn unescape(escaped_byte: u8) -> u8 {
+fn unescape(escaped_byte: u8) -> Result<u8> {
     match escaped_byte {
-        ESC_FF => 0xFF,
-        ESC_7F => 0x7F,
-        x      => if x & 0x60 != 0 { x ^ 0x40 } else { x },
+        ESC_FF                 => Ok(0xFF),
+        ESC_7F                 => Ok(0x7F),
+        x if x & 0x60 == 0x40  => Ok(x ^ 0x40),
+        x                      => Err(ProtocolError::InvalidEscape(x).into()),
+    }
+}
+
+/// Unescapes the byte following ZLDE. ESC_8 sets the 8th bit of the byte
+/// after it, which may be escaped itself.
+async fn unescape_next<R>(r: &mut R, escaped_byte: u8) -> Result<u8>
+    where R: AsyncRead + Unpin {
+
+    if escaped_byte != ESC_8 {
+        return unescape(escaped_byte);
+    }
+
+    match read_byte_skip_flow(r).await? {
+        ZLDE => Ok(unescape(read_byte_skip_flow(r).await?)? | 0x80),
+        x    => Ok(x | 0x80),
     }
 }
 
-fn is_escaped(byte: u8) -> bool {
-	match byte {
-		ZCRCE | ZCRCG | ZCRCQ | ZCRCW => false,
-        _ => true,
-	}
+/// Runs a future reading in-memory input, which never waits
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    let mut cx = Context::from_waker(Waker::noop());
+    match pin!(f).poll(&mut cx) {
+        Poll::Ready(x) => x,
+        Poll::Pending  => unreachable!("reading a slice never waits"),
+    }
 }
 
 /// Reads out one byte
-async fn read_byte<R>(r: &mut R) -> Result<u8>
+pub async fn read_byte<R>(r: &mut R) -> Result<u8>
     where R: AsyncRead + Unpin {
     let mut b = [0; 1];
     r.read_exact(&mut b).await.map(|_| b[0]).map_err(|e| e.into())
 }
 
-/// Writes ZRINIT frame
-pub async fn write_zrinit<W>(w: &mut W) -> Result<()>
+/// XON or XOFF, with or without the high bit. Senders escape them, so
+/// they are never part of frames.
+pub fn is_flow_control(b: u8) -> bool {
+    matches!(b & 0x7f, XON | XOFF)
+}
+
+/// Reads out one byte, skipping XON and XOFF inserted by modems and
+/// terminal servers doing software flow control
+pub async fn read_byte_skip_flow<R>(r: &mut R) -> Result<u8>
+    where R: AsyncRead + Unpin {
+
+    loop {
+        match read_byte(r).await? {
+            x if is_flow_control(x) => continue,
+            x                       => return Ok(x),
+        }
+    }
+}
+
+/// Checks without waiting whether there is data to read
+pub async fn has_input<R>(r: &mut R) -> Result<bool>
+    where R: AsyncBufRead + Unpin {
+
+    poll_fn(|cx| match Pin::new(&mut *r).poll_fill_buf(cx) {
+        // the end of stream counts too, reading reports it
+        Poll::Ready(Ok(_))  => Poll::Ready(Ok(true)),
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+        Poll::Pending       => Poll::Ready(Ok(false)),
+    }).await
+}
+
+/// Discards input that has already arrived up to the next header
+/// (ZPAD ZDLE or ZPAD ZPAD), returns number of bytes discarded
+pub async fn purge<R>(r: &mut R) -> Result<u64>
+    where R: AsyncBufRead + Unpin {
+
+    let mut discarded = 0;
+
+    while has_input(r).await? {
+        let buf = r.fill_buf().await?;
+        if buf.is_empty() {
+            break; // end of stream
+        }
+
+        // a ZPAD at the end of the buffer may start a header
+        let header = (0..buf.len()).find(|&i| buf[i] == ZPAD &&
+            matches!(buf.get(i + 1), None | Some(&ZPAD) | Some(&ZLDE)));
+        let n = header.unwrap_or(buf.len());
+
+        r.consume(n);
+        discarded += n as u64;
+
+        if header.is_some() {
+            break;
+        }
+    }
+
+    Ok(discarded)
+}
+
+/// Writes a header
+async fn write_header<W>(w: &mut W, frame: &Frame) -> Result<()>
+    where W: AsyncWrite + Unpin {
+
+    event!(debug, frame = frame.type_name(), encoding = frame.encoding_name(), flags = ?frame.get_flags(), count = frame.get_count(), "header sent");
+    instrument::count_frame("zmodem_frames_sent_total", frame.type_name());
+    w.write_all(&frame.build()).await
+        .map_err(|e| e.into())
+}
+
+/// Writes ZRINIT frame advertising the receive buffer size, 0 for full
+/// streaming, and the transports we uncompress
+pub async fn write_zrinit<W>(w: &mut W, buffer_size: u16, escape: Escape, transports: &[Transport]) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZRINIT");
-    w.write_all(&Frame::new(ZHEX, ZRINIT).flags(&[0, 0, 0, 0x23]).build()).await?;
-    Ok(())
+    let [lo, hi] = buffer_size.to_le_bytes();
+    let mut flags = [lo, hi, 0, CANFC32 | CANOVIO | CANFDX];
+    escape.to_flags(&mut flags);
+    for transport in transports {
+        transport.advertise(&mut flags);
+    }
+    write_header(w, Frame::new(ZHEX, ZRINIT).flags(&flags)).await
+}
+
+/// Writes ZSINIT frame with an empty attention string
+pub async fn write_zsinit<W>(w: &mut W, enc: Encoding, escape: Escape) -> Result<()>
+    where W: AsyncWrite + Unpin {
+
+    debug!("write ZSINIT");
+    let mut flags = [0; 4];
+    escape.to_flags(&mut flags);
+    write_header(w, Frame::new(enc.header, ZSINIT).flags(&flags).escape(enc.escape)).await?;
+    write_zlde_data(w, enc, ZCRCW, &[0]).await
 }
 
 /// Writes ZRQINIT frame
@@ -216,25 +468,20 @@ pub async fn write_zrqinit<W>(w: &mut W) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZRQINIT");
-    w.write_all(&Frame::new(ZHEX, ZRQINIT).build()).await
-        .map_err(|e| e.into())
+    write_header(w, &Frame::new(ZHEX, ZRQINIT)).await
 }
 
 /// Writes ZFILE frame
-pub async fn write_zfile<W>(w: &mut W, filename: &str, filesize: Option<u32>) -> Result<()>
+pub async fn write_zfile<W>(w: &mut W, enc: Encoding, info: &FileInfo) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZFILE");
-    w.write_all(&Frame::new(ZBIN32, ZFILE).build()).await?;
-
-    let mut zfile_data = format!("{}\0", filename);
-    if let Some(size) = filesize {
-        zfile_data += &format!(" {}", size);
-    }
-    zfile_data += &format!("\0");
+    let (mut flags, zfile_data) = info.to_zfile();
+    enc.transport.to_zfile(&mut flags);
+    write_header(w, Frame::new(enc.header, ZFILE).flags(&flags).escape(enc.escape)).await?;
 
-    debug!("ZFILE supplied data: {}", zfile_data);
-    write_zlde_data(w, ZCRCW, zfile_data.as_bytes()).await
+    debug!("ZFILE supplied data: {}", String::from_utf8_lossy(&zfile_data));
+    write_zlde_data(w, enc, ZCRCW, &zfile_data).await
 }
 
 /// Writes ZACK frame
@@ -242,8 +489,15 @@ pub async fn write_zack<W>(w: &mut W, count: u32) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZACK bytes={}", count);
-    w.write_all(&Frame::new(ZHEX, ZACK).count(count).build()).await
-        .map_err(|e| e.into())
+    write_header(w, Frame::new(ZHEX, ZACK).count(count)).await
+}
+
+/// Writes ZSKIP frame
+pub async fn write_zskip<W>(w: &mut W) -> Result<()>
+    where W: AsyncWrite + Unpin {
+
+    debug!("write ZSKIP");
+    write_header(w, &Frame::new(ZHEX, ZSKIP)).await
 }
 
 /// Writes ZFIN frame
@@ -251,8 +505,7 @@ pub async fn write_zfin<W>(w: &mut W) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZFIN");
-    w.write_all(&Frame::new(ZHEX, ZFIN).build()).await
-        .map_err(|e| e.into())
+    write_header(w, &Frame::new(ZHEX, ZFIN)).await
 }
 
 /// Writes ZNAK frame
@@ -260,8 +513,15 @@ pub async fn write_znak<W>(w: &mut W) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZNAK");
-    w.write_all(&Frame::new(ZHEX, ZNAK).build()).await
-        .map_err(|e| e.into())
+    write_header(w, &Frame::new(ZHEX, ZNAK)).await
+}
+
+/// Writes ZFERR frame
+pub async fn write_zferr<W>(w: &mut W) -> Result<()>
+    where W: AsyncWrite + Unpin {
+
+    debug!("write ZFERR");
+    write_header(w, &Frame::new(ZHEX, ZFERR)).await
 }
 
 /// Writes ZRPOS frame
@@ -269,59 +529,51 @@ pub async fn write_zrpos<W>(w: &mut W, count: u32) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZRPOS bytes={}", count);
-    w.write_all(&Frame::new(ZHEX, ZRPOS).count(count).build()).await
-        .map_err(|e| e.into())
+    write_header(w, Frame::new(ZHEX, ZRPOS).count(count)).await
 }
 
 /// Writes ZDATA frame
-pub async fn write_zdata<W>(w: &mut W, offset: u32) -> Result<()>
+pub async fn write_zdata<W>(w: &mut W, enc: Encoding, offset: u32) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZDATA offset={}", offset);
-    w.write_all(&Frame::new(ZBIN32, ZDATA).count(offset).build()).await
-        .map_err(|e| e.into())
+    write_header(w, Frame::new(enc.header, ZDATA).count(offset).escape(enc.escape)).await
 }
 
 /// Writes ZEOF frame
-pub async fn write_zeof<W>(w: &mut W, offset: u32) -> Result<()>
+pub async fn write_zeof<W>(w: &mut W, enc: Encoding, offset: u32) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     debug!("write ZEOF offset={}", offset);
-    w.write_all(&Frame::new(ZBIN32, ZEOF).count(offset).build()).await
-        .map_err(|e| e.into())
+    write_header(w, Frame::new(enc.header, ZEOF).count(offset).escape(enc.escape)).await
 }
 
-pub async fn write_zlde_data<W>(w: &mut W, zcrc_byte: u8, data: &[u8]) -> Result<()>
+pub async fn write_zlde_data<W>(w: &mut W, enc: Encoding, zcrc_byte: u8, data: &[u8]) -> Result<()>
     where W: AsyncWrite + Unpin {
 
-    if log_enabled!(Debug) {
-        debug!("  ZCRC{} subpacket, size = {}",
-               match zcrc_byte {
-                   ZCRCE => "E",
-                   ZCRCG => "G",
-                   ZCRCQ => "Q",
-                   ZCRCW => "W",
-                   _     => "?",
-               },
-               data.len());
-    }
+    debug!("  {} subpacket, size = {}", terminator(zcrc_byte), data.len());
+    event!(trace, end = terminator(zcrc_byte), len = data.len(), "subpacket sent");
+    instrument::record("zmodem_subpacket_bytes", data.len() as f64);
 
-    let crc = get_crc32(data, Some(zcrc_byte));
+    let crc = match enc.header {
+        ZBIN32 => get_crc32(data, Some(zcrc_byte)).to_vec(),
+        _      => get_crc16(data, Some(zcrc_byte)).to_vec(),
+    };
 
-    write_escape(w, data).await?;
-    w.write(&[ZLDE, zcrc_byte]).await?;
-    write_escape(w, &crc).await?;
+    write_escape(w, data, enc.escape).await?;
+    w.write_all(&[ZLDE, zcrc_byte]).await?;
+    write_escape(w, &crc, enc.escape).await?;
 
     Ok(())
 }
 
-async fn write_escape<W>(w: &mut W, data: &[u8]) -> Result<()>
+async fn write_escape<W>(w: &mut W, data: &[u8], escape: Escape) -> Result<()>
     where W: AsyncWrite + Unpin {
 
     //let mut w = io::BufWriter::new(w);
 
     let mut esc_data = Vec::with_capacity(data.len() + data.len()/10);
-    escape_buf(data, &mut esc_data);
+    escape_buf(data, &mut esc_data, escape);
     w.write_all(&esc_data).await
         .map_err(|e| e.into())
 }
@@ -335,24 +587,43 @@ pub async fn write_over_and_out<W>(w: &mut W) -> Result<()>
 }
 
 
-pub fn escape_buf(src: &[u8], dst: &mut Vec<u8>) {
-    for x in src {
-        match *x {
-            0xFF => dst.extend_from_slice(&[ZLDE, ESC_FF]),
-            0x7F => dst.extend_from_slice(&[ZLDE, ESC_7F]),
-            0x10 | 0x90 | 0x11 | 0x91 | 0x13 | 0x93
-                 => dst.extend_from_slice(&[ZLDE, x ^ 0x40]),
-            ZLDE => dst.extend_from_slice(&[ZLDE, ZLDEE]),
-            x    => dst.push(x),
-        };
+/// Escapes ZLDE, flow control characters, 0x7F and 0xFF and whatever
+/// else `escape` asks for
+pub fn escape_buf(src: &[u8], dst: &mut Vec<u8>, escape: Escape) {
+    for &x in src {
+        escape_byte(x, dst, escape);
     }
 }
 
+fn escape_byte(x: u8, dst: &mut Vec<u8>, escape: Escape) {
+    // the byte sent before `dst` is unknown, it may be '@'
+    let after_at = dst.last().is_none_or(|&b| b & 0x7f == b'@');
+
+    match x {
+        0xFF => dst.extend_from_slice(&[ZLDE, ESC_FF]),
+        0x7F => dst.extend_from_slice(&[ZLDE, ESC_7F]),
+        x if escape.eight_bit && x & 0x80 != 0 => {
+            dst.extend_from_slice(&[ZLDE, ESC_8]);
+            escape_byte(x & 0x7f, dst, escape);
+        },
+        0x10 | 0x90 | 0x11 | 0x91 | 0x13 | 0x93
+             => dst.extend_from_slice(&[ZLDE, x ^ 0x40]),
+        ZLDE => dst.extend_from_slice(&[ZLDE, ZLDEE]),
+        x if escape.controls && x & 0x60 == 0
+             => dst.extend_from_slice(&[ZLDE, x ^ 0x40]),
+        x if escape.telnet && x & 0x7f == b'\r' && after_at
+             => dst.extend_from_slice(&[ZLDE, x ^ 0x40]),
+        x    => dst.push(x),
+    };
+}
+
+#[cfg(test)]
 mod tests {
     #![allow(unused_imports)]
 
     use crate::consts::*;
     use crate::frame::*;
+    use crate::error::ZmodemError;
     use super::*;
 
     #[tokio::test]
@@ -363,6 +634,9 @@ mod tests {
         let v = vec![ZPAD, ZPAD, ZLDE];
         assert!(find_zpad(&mut v.as_slice()).await.unwrap());
 
+        let v = vec![ZPAD, XON, ZPAD, XOFF | 0x80, ZLDE];
+        assert!(find_zpad(&mut v.as_slice()).await.unwrap());
+
         let v = vec![ZLDE];
         assert!(!find_zpad(&mut v.as_slice()).await.unwrap());
 
@@ -373,6 +647,22 @@ mod tests {
         assert!(!find_zpad(&mut v.as_slice()).await.unwrap());
     }
 
+    #[tokio::test]
+    async fn test_purge() {
+        let v = vec![1, 2, ZPAD, 3, ZPAD, ZPAD, ZLDE, ZHEX];
+        let mut r = v.as_slice();
+        assert_eq!(purge(&mut r).await.unwrap(), 4);
+        assert_eq!(r, [ZPAD, ZPAD, ZLDE, ZHEX]);
+
+        let v = vec![1, 2, ZPAD];
+        let mut r = v.as_slice();
+        assert_eq!(purge(&mut r).await.unwrap(), 2);
+
+        let v = vec![0; 10];
+        let mut r = v.as_slice();
+        assert_eq!(purge(&mut r).await.unwrap(), 10);
+    }
+
     #[tokio::test]
     async fn test_read_exact_unescaped() {
         let i = [0; 32];
@@ -380,62 +670,153 @@ mod tests {
         read_exact_unescaped(&i[..], &mut o).await.unwrap();
         assert_eq!(i, o);
 
-        let i = [ZLDE, b'm', ZLDE, b'l', ZLDE, 0x6f];
+        let i = [ZLDE, b'm', ZLDE, b'l', ZLDE, 0x4f];
         let mut o = [0; 3];
         read_exact_unescaped(&i[..], &mut o).await.unwrap();
-        assert_eq!(o, [0xff, 0x7f, 0x2f]);
+        assert_eq!(o, [0xff, 0x7f, 0x0f]);
 
         let i = [ZLDE, b'm', 0, 2, ZLDE, b'l'];
         let mut o = [0; 4];
         read_exact_unescaped(&i[..], &mut o).await.unwrap();
         assert_eq!(o, [0xff, 0, 2, 0x7f]);
+
+        // flow control inserted by the line
+        let i = [XOFF, 1, ZLDE, XON, b'm', 2, XON | 0x80];
+        let mut o = [0; 3];
+        read_exact_unescaped(&i[..], &mut o).await.unwrap();
+        assert_eq!(o, [1, 0xff, 2]);
+
+        // only ZCRC* and bytes with bit 6 set may follow ZLDE
+        let i = [ZLDE, 0x6f];
+        let mut o = [0; 1];
+        assert!(matches!(read_exact_unescaped(&i[..], &mut o).await,
+                         Err(ZmodemError::ProtocolError(ProtocolError::InvalidEscape(0x6f)))));
     }
 
     #[tokio::test]
     async fn test_parse_header() {
         let i = [ZHEX, b'0', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'a', b'7', b'5', b'2'];
         assert_eq!(
-            &mut parse_header(&i[..]).await.unwrap().unwrap(),
+            &mut parse_header(&i[..]).await.unwrap(),
             Frame::new(ZHEX, 1).flags(&[0x1, 0x2, 0x3, 0x4]));
 
         let frame = 1;
         let i = [ZBIN, frame, 0xa, 0xb, 0xc, 0xd, 0xa6, 0xcb];
         assert_eq!(
-            &mut parse_header(&i[..]).await.unwrap().unwrap(),
+            &mut parse_header(&i[..]).await.unwrap(),
             Frame::new(ZBIN, frame).flags(&[0xa, 0xb, 0xc, 0xd]));
 
         let frame = 1;
         let i = [ZBIN32, frame, 0xa, 0xb, 0xc, 0xd, 0x99, 0xe2, 0xae, 0x4a];
         assert_eq!(
-            &mut parse_header(&i[..]).await.unwrap().unwrap(),
+            &mut parse_header(&i[..]).await.unwrap(),
             Frame::new(ZBIN32, frame).flags(&[0xa, 0xb, 0xc, 0xd]));
 
         let frame = 1;
         let i = [ZBIN, frame, 0xa, ZLDE, b'l', 0xd, ZLDE, b'm', 0x5e, 0x6f];
         assert_eq!(
-            &mut parse_header(&i[..]).await.unwrap().unwrap(),
+            &mut parse_header(&i[..]).await.unwrap(),
             Frame::new(ZBIN, frame).flags(&[0xa, 0x7f, 0xd, 0xff]));
 
         let frame = 1;
         let i = [0xaa, frame, 0xa, 0xb, 0xc, 0xd, 0xf, 0xf];
-        assert_eq!(parse_header(&i[..]).await.unwrap(), None);
+        assert!(matches!(parse_header(&i[..]).await,
+                         Err(ZmodemError::ProtocolError(ProtocolError::BadHeaderEncoding))));
+
+        let i = [ZBIN, frame, 0xa, 0xb, 0xc, 0xd, 0xa6, 0xcc];
+        assert!(matches!(parse_header(&i[..]).await,
+                         Err(ZmodemError::ProtocolError(ProtocolError::BadCrc { expected: 0xa6cb, actual: 0xa6cc }))));
+    }
+
+    #[tokio::test]
+    async fn test_escape_buf() {
+        let mut v = vec![];
+        escape_buf(&[0, 0x0d, ZLDE, 0x11, 0x7f, 0x8d, b'a'], &mut v, Escape::MINIMAL);
+        assert_eq!(&v[..], [0, 0x0d, ZLDE, ZLDEE, ZLDE, 0x51, ZLDE, ESC_7F, 0x8d, b'a']);
+
+        let mut v = vec![];
+        escape_buf(&[0, 0x0d, ZLDE, 0x8d, b'a'], &mut v, Escape::CONTROLS);
+        assert_eq!(&v[..], [ZLDE, 0x40, ZLDE, 0x4d, ZLDE, ZLDEE, ZLDE, 0xcd, b'a']);
+
+        let mut o = [0; 5];
+        read_exact_unescaped(&v[..], &mut o).await.unwrap();
+        assert_eq!(o, [0, 0x0d, ZLDE, 0x8d, b'a']);
+
+        // the byte before the first one is unknown
+        let mut v = vec![];
+        escape_buf(&[0x0d, b'@', 0x0d, b'a', 0x0d, 0xc0, 0x8d], &mut v, Escape::TELNET);
+        assert_eq!(&v[..], [ZLDE, 0x4d, b'@', ZLDE, 0x4d, b'a', 0x0d, 0xc0, ZLDE, 0xcd]);
+
+        let mut o = [0; 7];
+        read_exact_unescaped(&v[..], &mut o).await.unwrap();
+        assert_eq!(o, [0x0d, b'@', 0x0d, b'a', 0x0d, 0xc0, 0x8d]);
+
+        let mut v = vec![];
+        escape_buf(&[0x80, 0xc1, 0x91, 0xff, 0x98, b'a'], &mut v, Escape::SEVEN_BIT);
+        assert_eq!(&v[..], [ZLDE, ESC_8, 0, ZLDE, ESC_8, b'A', ZLDE, ESC_8, ZLDE, 0x51,
+                            ZLDE, ESC_FF, ZLDE, ESC_8, ZLDE, ZLDEE, b'a']);
+        assert!(v.iter().all(|x| x & 0x80 == 0));
+
+        // only our own flag asks for it
+        assert_eq!(Escape::from_flags(&[0, 0, 0, ESC8]), Escape::MINIMAL);
+        assert_eq!(Escape::from_flags(&[0, 0, XESC8, 0]), Escape::SEVEN_BIT);
+
+        let mut o = [0; 6];
+        read_exact_unescaped(&v[..], &mut o).await.unwrap();
+        assert_eq!(o, [0x80, 0xc1, 0x91, 0xff, 0x98, b'a']);
+
+        v.extend_from_slice(&[ZLDE, ZCRCW]);
+        v.extend_from_slice(&get_crc16(&[0x80, 0xc1, 0x91, 0xff, 0x98, b'a'], Some(ZCRCW)));
+        let mut o = vec![];
+        assert_eq!(recv_zlde_frame(ZBIN, &mut v.as_slice(), &mut o, 1024).await.unwrap(), ZCRCW);
+        assert_eq!(&o[..], [0x80, 0xc1, 0x91, 0xff, 0x98, b'a']);
     }
 
     #[tokio::test]
     async fn test_recv_zlde_frame() {
         let i = vec![ZLDE, ZCRCE, 237, 174];
         let mut v = vec![];
-        assert_eq!(recv_zlde_frame(ZBIN, &mut i.as_slice(), &mut v).await.unwrap(), Some(ZCRCE));
+        assert_eq!(recv_zlde_frame(ZBIN, &mut i.as_slice(), &mut v, 1024).await.unwrap(), ZCRCE);
         assert_eq!(&v[..], []);
 
-        let i = vec![ZLDE, 0x00, ZLDE, ZCRCW, 221, 205];
+        let i = vec![ZLDE, 0x40, ZLDE, ZCRCW, 221, 205];
         let mut v = vec![];
-        assert_eq!(recv_zlde_frame(ZBIN, &mut i.as_slice(), &mut v).await.unwrap(), Some(ZCRCW));
+        assert_eq!(recv_zlde_frame(ZBIN, &mut i.as_slice(), &mut v, 1024).await.unwrap(), ZCRCW);
         assert_eq!(&v[..], [0x00]);
 
-        let i = vec![0, 1, 2, 3, 4, ZLDE, 0x60, ZLDE, 0x60, ZLDE, ZCRCQ, 85, 114, 241, 70];
+        let i = vec![0, 1, 2, 3, 4, ZLDE, 0x4d, ZLDE, 0x4d, ZLDE, ZCRCQ, 9, 253, 78, 86];
         let mut v = vec![];
-        assert_eq!(recv_zlde_frame(ZBIN32, &mut i.as_slice(), &mut v).await.unwrap(), Some(ZCRCQ));
-        assert_eq!(&v[..], [0, 1, 2, 3, 4, 0x20, 0x20]);
+        assert_eq!(recv_zlde_frame(ZBIN32, &mut i.as_slice(), &mut v, 1024).await.unwrap(), ZCRCQ);
+        assert_eq!(&v[..], [0, 1, 2, 3, 4, 0x0d, 0x0d]);
+
+        let i = vec![0, 1, XOFF, 2, 3, XON, 4, ZLDE, 0x4d, ZLDE, XON, 0x4d, ZLDE, XOFF, ZCRCQ, 9, XON, 253, 78, 86];
+        let mut v = vec![];
+        assert_eq!(recv_zlde_frame(ZBIN32, &mut i.as_slice(), &mut v, 1024).await.unwrap(), ZCRCQ);
+        assert_eq!(&v[..], [0, 1, 2, 3, 4, 0x0d, 0x0d]);
+
+        let i = vec![0, 1, ZLDE, 0x60, ZLDE, ZCRCQ, 0, 0, 0, 0];
+        let mut v = vec![];
+        assert!(matches!(recv_zlde_frame(ZBIN32, &mut i.as_slice(), &mut v, 1024).await,
+                         Err(ZmodemError::ProtocolError(ProtocolError::InvalidEscape(0x60)))));
+
+        let i = vec![0; 100];
+        let mut v = vec![];
+        assert!(matches!(recv_zlde_frame(ZBIN32, &mut i.as_slice(), &mut v, 64).await,
+                         Err(ZmodemError::ProtocolError(ProtocolError::SubpacketTooLong(64)))));
+
+        // the terminator and CRC in the same chunk
+        let mut i = vec![b'a'; 2000];
+        i.extend_from_slice(&[ZLDE, ZCRCW]);
+        i.extend_from_slice(&get_crc16(&[b'a'; 2000], Some(ZCRCW)));
+        let mut v = vec![];
+        assert!(matches!(recv_zlde_frame(ZBIN, &mut i.as_slice(), &mut v, 1024).await,
+                         Err(ZmodemError::ProtocolError(ProtocolError::SubpacketTooLong(1024)))));
+
+        // truncated input is an error, not a panic
+        for i in [&[][..], &[1, 2], &[1, ZLDE], &[1, ZLDE, ZCRCW, 0]] {
+            let mut v = vec![];
+            assert!(matches!(recv_zlde_frame(ZBIN32, &mut &i[..], &mut v, 1024).await,
+                             Err(ZmodemError::IoError(_))));
+        }
     }
 }
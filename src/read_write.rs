@@ -5,6 +5,9 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::io;
 
 pin_project! {
+    /// Joins separate reader and writer halves into one duplex stream as
+    /// required by `send` and `recv`, e.g. child process pipes, FIFOs,
+    /// `tokio::io::split` outputs or serial port halves
     pub struct AsyncReadWrite<R, W> {
         #[pin]
         inner_read:  R,
@@ -14,24 +17,28 @@ pin_project! {
 }
 
 impl<R, W> AsyncReadWrite<R, W>
-    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    where R: AsyncRead, W: AsyncWrite {
     pub fn new(read: R, write: W) -> Self {
         Self {
             inner_read:  read,
             inner_write: write,
         }
     }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.inner_read, self.inner_write)
+    }
 }
 
 impl<R, W> AsyncRead for AsyncReadWrite<R, W>
-    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    where R: AsyncRead, W: AsyncWrite {
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut io::ReadBuf<'_>) -> Poll<io::Result<()>> {
         self.project().inner_read.poll_read(cx, buf)
     }
 }
 
 impl<R, W> AsyncWrite for AsyncReadWrite<R, W>
-    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    where R: AsyncRead, W: AsyncWrite {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         self.project().inner_write.poll_write(cx, buf)
     }
@@ -44,3 +51,26 @@ impl<R, W> AsyncWrite for AsyncReadWrite<R, W>
         self.project().inner_write.poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_split_halves() {
+        let (a, b) = io::duplex(64);
+        let (a_read, a_write) = io::split(a);
+        let mut rw = AsyncReadWrite::new(a_read, a_write);
+        let (mut b_read, mut b_write) = io::split(b);
+
+        rw.write_all(b"ping").await.unwrap();
+        b_write.write_all(b"pong").await.unwrap();
+
+        let mut buf = [0; 4];
+        b_read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        rw.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
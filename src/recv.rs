@@ -1,18 +1,34 @@
-use std::{thread, time};
-use std::str::from_utf8;
-use tokio::io::{AsyncRead, AsyncWrite};
+use bytes::Bytes;
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Context, Poll};
+use std::{io, result, thread, time};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
 
-use crate::error::Result;
+use crate::error::{Result, ProtocolError};
 use crate::consts::*;
+use crate::convert::Converter;
+use crate::file_info::FileInfo;
+use crate::instrument;
 use crate::proto::*;
 use crate::rwlog;
+use crate::transport::Transport;
 use crate::frame::*;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum State {
     /// Sending ZRINIT
     SendingZRINIT,
 
+    /// Processing ZSINIT supplementary data
+    ProcessingZSINIT,
+
     /// Processing ZFILE supplementary data
     ProcessingZFILE,
 
@@ -31,86 +47,456 @@ impl State {
         State::SendingZRINIT
     }
 
-    fn next(self, frame: &Frame) -> State {
-        match (self, frame.get_frame_type()) {
+    /// Unexpected frames leave the state unchanged
+    fn next(self, frame: &Frame) -> result::Result<State, ProtocolError> {
+        Ok(match (self, frame.get_frame_type()) {
+            (State::SendingZRINIT, ZSINIT)  => State::ProcessingZSINIT,
             (State::SendingZRINIT, ZFILE)   => State::ProcessingZFILE,
+            (State::SendingZRINIT, ZFIN)    => State::Done,
             (State::SendingZRINIT, _)       => State::SendingZRINIT,
 
+            (State::ProcessingZSINIT, ZSINIT) => State::ProcessingZSINIT,
+            (State::ProcessingZSINIT, ZFILE)  => State::ProcessingZFILE,
+            (State::ProcessingZSINIT, ZFIN)   => State::Done,
+            (State::ProcessingZSINIT, _)      => State::SendingZRINIT,
+
             (State::ProcessingZFILE, ZDATA) => State::ReceivingData,
-            (State::ProcessingZFILE, _)     => State::ProcessingZFILE,
+            (State::ProcessingZFILE, ZEOF)  => State::CheckingData,
+            (State::ProcessingZFILE, ZFILE) => State::ProcessingZFILE,
 
             (State::ReceivingData, ZDATA)   => State::ReceivingData,
             (State::ReceivingData, ZEOF)    => State::CheckingData,
 
             (State::CheckingData, ZDATA)    => State::ReceivingData,
+            (State::CheckingData, ZFILE)    => State::ProcessingZFILE,
             (State::CheckingData, ZFIN)     => State::Done,
 
-            (s, _) => {
-               error!("Unexpected (state, frame) combination: {:#?} {}", s, frame);
-               s // don't change current state
-            },
+            (s, _) => return Err(ProtocolError::UnexpectedFrame {
+                state: format!("{:?}", s),
+                frame: frame.get_frame_type(),
+            }),
+        })
+    }
+}
+
+/// Statistics of a finished transfer
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Statistics {
+    /// Files received completely
+    pub files: u32,
+    /// File data received in bytes
+    pub bytes: u64,
+    /// Data subpackets with bad CRC
+    pub crc_errors: u32,
+    /// Damaged or garbled headers
+    pub bad_headers: u32,
+    /// Requests to resend data after errors (ZRPOS)
+    pub rewinds: u32,
+    /// Frames ignored while waiting for data at the expected offset
+    pub ignored_frames: u32,
+    /// Bytes of input discarded after errors
+    pub discarded: u64,
+}
+
+/// Receiver options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Receive buffer size advertised in ZRINIT, 0 to accept streaming
+    /// without limits. Senders keep subpackets and windows within it.
+    pub buffer_size: u16,
+
+    /// Longest data subpacket accepted, longer ones are treated as damaged
+    pub max_subpacket: usize,
+
+    /// Time to wait for a frame before asking the sender again
+    pub timeout: time::Duration,
+
+    /// Identifies the remote in the session span of the `tracing`
+    /// feature and labels the metrics of the `metrics` feature, e.g. a
+    /// port name
+    pub peer: String,
+
+    /// Escaping the sender is asked for in ZRINIT, e.g. the 8th bit on a 7
+    /// bit line; telnet escaping isn't asked for. Our own frames are hex,
+    /// which needs none.
+    pub escape: Escape,
+
+    /// Compressions advertised in ZRINIT, files sent with others are
+    /// skipped. None by default, as lrzsz.
+    pub transports: Vec<Transport>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            buffer_size:   0,
+            max_subpacket: MAX_SUBPACKET_SIZE,
+            timeout:       TIMEOUT,
+            peer:          String::new(),
+            escape:        Escape::MINIMAL,
+            transports:    Vec::new(),
         }
     }
 }
 
-/// Receives data by Z-Modem protocol
-pub async fn recv<RW, W>(rw: RW, mut w: W) -> Result<usize>
+impl Options {
+    /// Longest subpacket accepted considering the advertised buffer size
+    fn subpacket_limit(&self) -> usize {
+        match self.buffer_size {
+            0 => self.max_subpacket,
+            x => self.max_subpacket.min(x as usize),
+        }
+    }
+}
+
+/// Default limit of subpacket length, the largest subpacket of ZMODEM is 8K
+const MAX_SUBPACKET_SIZE: usize = 8192;
+
+/// Receiver's decision about a file offered by the sender
+pub enum Action<W> {
+    /// Write file data to `W` starting at the given offset, non-zero to resume
+    Accept(W, u32),
+
+    /// Answer with ZSKIP, the sender proceeds with the next file
+    Skip,
+}
+
+/// Receives data by Z-Modem protocol.
+/// Only the first file of a batch is written to `w`, the rest are skipped.
+pub async fn recv<RW, W>(rw: RW, w: W) -> Result<usize>
     where RW: AsyncRead + AsyncWrite + Unpin,
           W:  AsyncWrite + Unpin
+{
+    let mut w = Some(w);
+    let open = |_: &FileInfo| match w.take() {
+        Some(w) => Action::Accept(w, 0),
+        None    => Action::Skip,
+    };
+
+    let (_, stats) = instrument::session(recv_session(rw, open, &Options::default()), "recv", "").await?;
+    Ok(stats.bytes as usize)
+}
+
+/// Receives a batch of files by Z-Modem protocol.
+/// `open` is called for every ZFILE and decides where its content goes,
+/// `W` is shut down once the file is complete.
+/// Returns descriptions of the completely received files.
+pub async fn recv_files<RW, W, F>(rw: RW, open: F, options: &Options) -> Result<(Vec<FileInfo>, Statistics)>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          W:  AsyncWrite + Unpin,
+          F:  FnMut(&FileInfo) -> Action<W>
+{
+    instrument::session(recv_session(rw, open, options), "recv", &options.peer).await
+}
+
+/// Receives a batch of files by Z-Modem protocol as streams.
+/// The returned future runs the session and has to be polled alongside
+/// `Files`, which yields every accepted file with a stream of its data.
+/// Data isn't acknowledged until the stream takes it; dropping a
+/// `FileData` discards the rest of the file.
+pub fn recv_stream<RW>(rw: RW, options: &Options) -> (impl Future<Output = Result<(Vec<FileInfo>, Statistics)>>, Files)
+    where RW: AsyncRead + AsyncWrite + Unpin
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let open = move |info: &FileInfo| {
+        let (w, r) = tokio::io::duplex(STREAM_BUF_SIZE);
+        let done = Arc::new(AtomicBool::new(false));
+        let data = FileData { r, done: done.clone(), buf: vec![0; STREAM_BUF_SIZE], ended: false };
+
+        match tx.send((info.clone(), data)) {
+            Ok(_)  => Action::Accept(FileWriter { w, done }, 0),
+            Err(_) => Action::Skip,
+        }
+    };
+
+    let options = options.clone();
+    (async move { recv_files(rw, open, &options).await }, Files { rx })
+}
+
+/// Data buffered between the session and a `FileData` consumer
+const STREAM_BUF_SIZE: usize = 8192;
+
+/// Stream of files received by `recv_stream`
+pub struct Files {
+    rx: mpsc::UnboundedReceiver<(FileInfo, FileData)>,
+}
+
+impl Stream for Files {
+    type Item = (FileInfo, FileData);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Stream of verified data of one file received by `recv_stream`.
+/// Ends with an error if the session fails before the file is complete.
+pub struct FileData {
+    r:     DuplexStream,
+    done:  Arc<AtomicBool>,
+    buf:   Vec<u8>,
+    ended: bool,
+}
+
+impl Stream for FileData {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.ended {
+            return Poll::Ready(None);
+        }
+
+        let mut buf = ReadBuf::new(&mut this.buf);
+        if let Err(e) = ready!(Pin::new(&mut this.r).poll_read(cx, &mut buf)) {
+            this.ended = true;
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        match buf.filled() {
+            [] => {
+                this.ended = true;
+                match this.done.load(Ordering::Acquire) {
+                    true  => Poll::Ready(None),
+                    false => Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "transfer aborted")))),
+                }
+            },
+            data => Poll::Ready(Some(Ok(Bytes::copy_from_slice(data)))),
+        }
+    }
+}
+
+pin_project! {
+    /// Session side of `FileData`
+    struct FileWriter {
+        #[pin]
+        w:    DuplexStream,
+        done: Arc<AtomicBool>,
+    }
+}
+
+impl AsyncWrite for FileWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match ready!(self.project().w.poll_write(cx, buf)) {
+            // FileData is dropped, discard the data
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Poll::Ready(Ok(buf.len())),
+            x => Poll::Ready(x),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().w.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        this.done.store(true, Ordering::Release);
+        this.w.poll_shutdown(cx)
+    }
+}
+
+async fn recv_session<RW, W, F>(rw: RW, mut open: F, options: &Options) -> Result<(Vec<FileInfo>, Statistics)>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          W:  AsyncWrite + Unpin,
+          F:  FnMut(&FileInfo) -> Action<W>
 {
     let mut rw_log = rwlog::ReadWriteLog::new(rw);
     let mut count = 0;
+    let mut stats = Statistics::default();
+    // ZRPOS is sent, data at other offsets is stale
+    let mut resync = false;
+    let mut file: Option<(FileInfo, W, Converter, Transport)> = None;
+    let mut received = Vec::new();
+    let mut unexpected = 0;
+    let mut timeouts = 0;
+    let mut errors = Errors::default();
 
     let mut state = State::new();
 
-    write_zrinit(&mut rw_log).await?;
+    write_zrinit(&mut rw_log, options.buffer_size, options.escape, &options.transports).await?;
 
     while state != State::Done {
-        if !find_zpad(&mut rw_log).await? {
-            continue;
-        }
+        let header = match timeout(options.timeout, recv_header(&mut rw_log)).await {
+            Ok(x)  => { timeouts = 0; x? },
+            Err(_) => {
+                error!("timeout waiting for a frame in state {:?}", state);
+                event!(warn, state = ?state, "timeout");
+                instrument::count("zmodem_timeouts_total", 1);
+                timeouts += 1;
+                if timeouts >= MAX_ERRORS {
+                    return Err(ProtocolError::Timeout.into());
+                }
+
+                // our request or the data answering it got lost
+                if file.is_some() {
+                    stats.rewinds += 1;
+                    instrument::count("zmodem_rewinds_total", 1);
+                    resync = true;
+                    write_zrpos(&mut rw_log, count).await?;
+                }
+                else {
+                    write_zrinit(&mut rw_log, options.buffer_size, options.escape, &options.transports).await?;
+                }
+                continue;
+            },
+        };
 
-        let frame = match parse_header(&mut rw_log).await? {
+        let frame = match header {
             Some(x) => x,
-            None    => { recv_error(&mut rw_log, &state, count).await?; continue },
+            None    => {
+                stats.bad_headers += 1;
+                // most likely a ZPAD in stale data
+                if resync {
+                    continue;
+                }
+                errors.add(count)?;
+                resync = recv_error(&mut rw_log, &state, count, &mut stats).await?;
+                continue;
+            },
         };
 
-        state = state.next(&frame);
+        if matches!(frame.get_frame_type(), ZABORT | ZFERR | ZCAN) {
+            error!("sender aborted the transfer: {}", frame);
+            return Err(ProtocolError::RemoteAborted.into());
+        }
+
+        state = match state.next(&frame) {
+            Ok(x)  => { unexpected = 0; x },
+            Err(e) => {
+                error!("{}", e);
+                event!(warn, error = %e, "unexpected frame");
+                unexpected += 1;
+                if unexpected >= MAX_ERRORS {
+                    return Err(e.into());
+                }
+                // the state is kept, but its actions are for other frames
+                continue;
+            },
+        };
         debug!("State: {:?}", state);
+        event!(debug, frame = frame.type_name(), state = ?state, "state");
 
         // do things according new state
         match state {
             State::SendingZRINIT => {
-                write_zrinit(&mut rw_log).await?;
+                write_zrinit(&mut rw_log, options.buffer_size, options.escape, &options.transports).await?;
+            },
+            State::ProcessingZSINIT => {
+                let mut buf = Vec::new();
+
+                if or_damaged(recv_zlde_frame(frame.get_header(), &mut rw_log, &mut buf, options.subpacket_limit()).await)?.is_none() {
+                    errors.add(count)?;
+                    write_znak(&mut rw_log).await?;
+                    continue;
+                }
+
+                // hex headers are all we send, its escaping doesn't matter
+                debug!("ZSINIT escape: {:?}", Escape::from_flags(&frame.get_flags()));
+                write_zack(&mut rw_log, 1).await?;
             },
             State::ProcessingZFILE => {
                 let mut buf = Vec::new();
 
-                if recv_zlde_frame(frame.get_header(), &mut rw_log, &mut buf).await?.is_none() {
+                if or_damaged(recv_zlde_frame(frame.get_header(), &mut rw_log, &mut buf, options.subpacket_limit()).await)?.is_none() {
+                    errors.add(count)?;
                     write_znak(&mut rw_log).await?;
+                    continue;
                 }
-                else {
+
+                let info = FileInfo::from_zfile(frame.get_flags(), &buf);
+                debug!("ZFILE supplied data: {:?}", info);
+                instrument::file(&info.name);
+
+                // our ZRPOS got lost, the sender repeats ZFILE
+                if file.as_ref().is_some_and(|(x, _, _, _)| x.name == info.name) {
                     write_zrpos(&mut rw_log, count).await?;
+                    continue;
+                }
 
-                    // TODO: process supplied data
-                    if let Ok(s) = from_utf8(&buf) {
-                        debug!(target: "proto", "ZFILE supplied data: {}", s);
-                    }
+                let transport = Transport::from_zfile(&frame.get_flags())
+                    .filter(|x| *x == Transport::None || options.transports.contains(x));
+                let transport = match transport {
+                    Some(x) => x,
+                    None    => {
+                        let flags = frame.get_flags();
+                        error!("Skipping {}, unsupported transport {} extended {:#04x}", info.name, flags[ZF2], flags[ZF3]);
+                        file = None;
+                        write_zskip(&mut rw_log).await?;
+                        state = State::SendingZRINIT;
+                        continue;
+                    },
+                };
+
+                match open(&info) {
+                    Action::Accept(w, offset) => {
+                        count = offset;
+                        file = Some((info.clone(), w, Converter::new(info.conversion), transport));
+                        write_zrpos(&mut rw_log, count).await?;
+                    },
+                    Action::Skip => {
+                        debug!("Skipping {}", info.name);
+                        file = None;
+                        write_zskip(&mut rw_log).await?;
+                        state = State::SendingZRINIT;
+                    },
                 }
             },
             State::ReceivingData => {
-                if frame.get_count() != count ||
-                    !recv_data(frame.get_header(), &mut count, &mut rw_log, &mut w).await? {
+                let (_, w, conv, transport) = match file.as_mut() {
+                    Some(x) => x,
+                    // data of a finished file crossed our ZRINIT
+                    None    => {
+                        state = State::CheckingData;
+                        write_zrinit(&mut rw_log, options.buffer_size, options.escape, &options.transports).await?;
+                        continue;
+                    },
+                };
+
+                if frame.get_count() != count {
+                    if resync {
+                        debug!("ignoring ZDATA at {}, waiting for {}", frame.get_count(), count);
+                        stats.ignored_frames += 1;
+                        continue;
+                    }
+                    error!("ZDATA offset mismatch: frame({}) != recv({})", frame.get_count(), count);
+                    errors.add(count)?;
+                    stats.rewinds += 1;
+                    instrument::count("zmodem_rewinds_total", 1);
+                    resync = true;
                     write_zrpos(&mut rw_log, count).await?;
+                    continue;
+                }
+
+                resync = false;
+                if !recv_data(frame.get_header(), &mut count, &mut rw_log, w, conv, *transport, options.subpacket_limit()).await? {
+                    stats.crc_errors += 1;
+                    errors.add(count)?;
+                    resync = recv_error(&mut rw_log, &state, count, &mut stats).await?;
                 }
             },
             State::CheckingData => {
                 if frame.get_count() != count {
                     error!("ZEOF offset mismatch: frame({}) != recv({})", frame.get_count(), count);
-                    // receiver ignores the ZEOF because a new zdata is coming
+                    // ZEOF may have crossed our ZRPOS, but the ZRPOS or ZDATA
+                    // may also have been lost, repeat it to be sure
+                    if !resync {
+                        errors.add(count)?;
+                    }
+                    stats.rewinds += 1;
+                    instrument::count("zmodem_rewinds_total", 1);
+                    resync = true;
+                    write_zrpos(&mut rw_log, count).await?;
                 }
                 else {
-                    write_zrinit(&mut rw_log).await?;
+                    if let Some((info, mut w, mut conv, _)) = file.take() {
+                        w.write_all(conv.finish()).await?;
+                        w.shutdown().await?;
+                        stats.files += 1;
+                        stats.bytes += count as u64;
+                        received.push(info);
+                    }
+                    write_zrinit(&mut rw_log, options.buffer_size, options.escape, &options.transports).await?;
                 }
             },
             State::Done => {
@@ -120,17 +506,50 @@ pub async fn recv<RW, W>(rw: RW, mut w: W) -> Result<usize>
         }
     }
 
-    Ok(count as usize)
+    Ok((received, stats))
+}
+
+/// Consecutive errors at the same offset. The sender doesn't get past
+/// them, e.g. sending subpackets longer than we accept, if they go on.
+#[derive(Debug, Default)]
+struct Errors {
+    offset: u32,
+    count:  usize,
+}
+
+impl Errors {
+    fn add(&mut self, offset: u32) -> Result<()> {
+        if offset != self.offset {
+            self.offset = offset;
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count >= MAX_ERRORS {
+            error!("too many errors at offset {}", offset);
+            return Err(ProtocolError::TooManyErrors.into());
+        }
+        Ok(())
+    }
 }
 
-async fn recv_error<W>(w: &mut W, state: &State, count: u32) -> Result<()>
-    where W: AsyncWrite + Unpin
+/// Drops the rest of damaged input and asks to resend it.
+/// Returns true if data at the other offsets is stale from now on.
+async fn recv_error<RW>(rw: &mut RW, state: &State, count: u32, stats: &mut Statistics) -> Result<bool>
+    where RW: AsyncBufRead + AsyncWrite + Unpin
 {
-    // TODO: flush input
+    stats.discarded += purge(rw).await?;
 
     match *state {
-        State::ReceivingData => write_zrpos(w, count).await,
-        _                    => write_znak(w).await,
+        State::ReceivingData => {
+            stats.rewinds += 1;
+            instrument::count("zmodem_rewinds_total", 1);
+            write_zrpos(rw, count).await?;
+            Ok(true)
+        },
+        _ => {
+            write_znak(rw).await?;
+            Ok(false)
+        },
     }
 }
 
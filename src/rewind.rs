@@ -0,0 +1,143 @@
+use pin_project_lite::pin_project;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::io;
+
+pin_project! {
+    /// Makes a non-seekable reader seekable within the last `capacity`
+    /// bytes read, enough to retransmit a window after ZRPOS. Seeking
+    /// forward reads and discards the data in between.
+    pub(crate) struct Rewind<R> {
+        #[pin]
+        inner:    R,
+        // data read from `inner`, ending at offset `end`
+        buf:      Vec<u8>,
+        capacity: usize,
+        end:      u64,
+        pos:      u64,
+        skip_to:  Option<u64>,
+    }
+}
+
+impl<R: AsyncRead> Rewind<R> {
+    pub(crate) fn new(inner: R, capacity: usize) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            capacity,
+            end: 0,
+            pos: 0,
+            skip_to: None,
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Rewind<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+
+        if *this.pos < *this.end {
+            let start = this.buf.len() - (*this.end - *this.pos) as usize;
+            let n = out.remaining().min(this.buf.len() - start);
+            out.put_slice(&this.buf[start..start + n]);
+            *this.pos += n as u64;
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = out.filled().len();
+        ready!(this.inner.poll_read(cx, out))?;
+
+        let data = &out.filled()[filled..];
+        keep(this.buf, *this.capacity, data);
+        *this.end += data.len() as u64;
+        *this.pos = *this.end;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead> AsyncSeek for Rewind<R> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.project();
+
+        let offset = match position {
+            SeekFrom::Start(x) => x,
+            _                  => return Err(io::Error::new(io::ErrorKind::Unsupported, "only seeking from start is supported")),
+        };
+
+        let start = *this.end - this.buf.len() as u64;
+        if offset < start {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("can't rewind to {}, retransmission buffer starts at {}", offset, start)));
+        }
+
+        if offset <= *this.end {
+            *this.pos = offset;
+        }
+        else {
+            *this.skip_to = Some(offset);
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let mut this = self.project();
+
+        while let Some(offset) = *this.skip_to {
+            let mut tmp = [0; 4096];
+            let len = tmp.len().min((offset - *this.end) as usize);
+            let mut tmp = ReadBuf::new(&mut tmp[..len]);
+            ready!(this.inner.as_mut().poll_read(cx, &mut tmp))?;
+
+            let data = tmp.filled();
+            keep(this.buf, *this.capacity, data);
+            *this.end += data.len() as u64;
+
+            // stop at the end of data like files do
+            if data.is_empty() || *this.end == offset {
+                *this.skip_to = None;
+                *this.pos = *this.end;
+            }
+        }
+
+        Poll::Ready(Ok(*this.pos))
+    }
+}
+
+/// Appends `data` to `buf` keeping at least the last `capacity` bytes
+fn keep(buf: &mut Vec<u8>, capacity: usize, data: &[u8]) {
+    buf.extend_from_slice(data);
+
+    // trim once the excess is large enough to amortize the move
+    if buf.len() > capacity * 2 {
+        buf.drain(..buf.len() - capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rewind() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        let mut r = Rewind::new(&data[..], 32);
+        let mut buf = [0; 64];
+
+        r.read_exact(&mut buf).await.unwrap();
+        assert_eq!(r.seek(SeekFrom::Start(40)).await.unwrap(), 40);
+        r.read_exact(&mut buf[..8]).await.unwrap();
+        assert_eq!(buf[..8], data[40..48]);
+
+        // forward seek skips data
+        assert_eq!(r.seek(SeekFrom::Start(200)).await.unwrap(), 200);
+        r.read_exact(&mut buf[..8]).await.unwrap();
+        assert_eq!(buf[..8], data[200..208]);
+
+        // the start of the data is gone
+        assert!(r.seek(SeekFrom::Start(0)).await.is_err());
+    }
+}
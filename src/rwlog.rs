@@ -6,61 +6,88 @@ use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader, ReadBuf};
 use pin_project_lite::pin_project;
 use pretty_hex::pretty_hex;
 
+use crate::instrument;
+#[cfg(feature = "tracing")]
+use hex::ToHex;
+
 pin_project! {
     pub struct ReadWriteLog<RW> {
         #[pin]
-        inner: BufReader<RW>,
+        inner: BufReader<Log<RW>>,
+    }
+}
+
+pin_project! {
+    /// Dumps data as it passes the wire, below the buffer so that every
+    /// byte is logged once however it's read
+    pub struct Log<RW> {
+        #[pin]
+        inner: RW,
     }
 }
 
 impl<RW: AsyncRead + AsyncWrite> ReadWriteLog<RW> {
     pub fn new(rw: RW) -> ReadWriteLog<RW> {
         ReadWriteLog {
-            inner: BufReader::new(rw),
+            inner: BufReader::new(Log { inner: rw }),
         }
     }
 
     pub fn into_inner(self) -> RW {
-        self.inner.into_inner()
+        self.inner.into_inner().inner
     }
 }
 
 impl<R: AsyncRead> AsyncRead for ReadWriteLog<R> {
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
-        match self.project().inner.poll_read(cx, buf) {
-            Poll::Ready(Ok(r)) => {
-                if log_enabled!(Debug) {
-                    debug!("In:");
-                    debug!("{}", pretty_hex(&buf.filled()));
-                }
-                Poll::Ready(Ok(r))
-            },
-            otherwise => otherwise,
-        }
+        self.project().inner.poll_read(cx, buf)
     }
 }
 
 impl<R: AsyncRead> AsyncBufRead for ReadWriteLog<R> {
 
     fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
-        match self.project().inner.poll_fill_buf(cx) {
+        self.project().inner.poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.project().inner.consume(amt)
+    }
+}
+
+impl<RW: AsyncWrite + AsyncRead> AsyncWrite for ReadWriteLog<RW> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Log<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match self.project().inner.poll_read(cx, buf) {
             Poll::Ready(Ok(r)) => {
                 if log_enabled!(Debug) {
                     debug!("In:");
-                    debug!("{}", pretty_hex(&r));
+                    debug!("{}", pretty_hex(&&buf.filled()[filled..]));
                 }
+                event!(trace, len = buf.filled().len() - filled, data = %(&buf.filled()[filled..]).to_hex(), "read");
+                instrument::count("zmodem_bytes_received_total", (buf.filled().len() - filled) as u64);
                 Poll::Ready(Ok(r))
             },
             otherwise => otherwise,
         }
     }
-
-    fn consume(self: Pin<&mut Self>, amt: usize) {
-        self.project().inner.consume(amt)
-    }
 }
 
-impl<RW: AsyncWrite + AsyncRead> AsyncWrite for ReadWriteLog<RW> {
+impl<RW: AsyncWrite> AsyncWrite for Log<RW> {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         match self.project().inner.poll_write(cx, buf) {
             Poll::Ready(Ok(n)) => {
@@ -68,6 +95,8 @@ impl<RW: AsyncWrite + AsyncRead> AsyncWrite for ReadWriteLog<RW> {
                     debug!("Out:");
                     debug!("{}", pretty_hex(&&buf[0..n]));
                 }
+                event!(trace, len = n, data = %(&buf[0..n]).to_hex(), "written");
+                instrument::count("zmodem_bytes_sent_total", n as u64);
                 Poll::Ready(Ok(n))
             },
             otherwise => otherwise,
@@ -82,4 +111,3 @@ impl<RW: AsyncWrite + AsyncRead> AsyncWrite for ReadWriteLog<RW> {
         self.project().inner.poll_shutdown(cx)
     }
 }
-
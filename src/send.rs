@@ -1,16 +1,127 @@
 use std::io::SeekFrom;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use std::result;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use std::time::Duration;
+use tokio::time::{timeout, timeout_at, Instant};
 
-use crate::error::Result;
+use crate::error::{Result, ProtocolError, ZmodemError};
 use crate::consts::*;
+use crate::file_info::FileInfo;
+use crate::instrument;
 use crate::proto::*;
+use crate::rewind::Rewind;
 use crate::rwlog;
+use crate::transport::Transport;
 use crate::frame::*;
+use crate::xmodem::{write_cancel, Check};
+use crate::ymodem;
 
 const SUBPACKET_SIZE: usize = 1024 * 8;
 const SUBPACKET_PER_ACK: usize = 10;
 
-#[derive(Debug, PartialEq)]
+/// Smallest subpacket of adaptive sizing
+const MIN_SUBPACKET_SIZE: usize = 32;
+/// Subpackets sent without errors before adaptive sizing doubles the size
+const CLEAN_RUN: u32 = 8;
+
+/// Sender options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Data subpacket size in bytes
+    pub block_size: usize,
+
+    /// Bytes sent before waiting for ZACK. Limited by the buffer size
+    /// advertised by the receiver in ZRINIT.
+    pub window: usize,
+
+    /// Use CRC-16 (ZBIN) instead of CRC-32 (ZBIN32). Also used when
+    /// the receiver doesn't advertise CANFC32.
+    pub crc16: bool,
+
+    /// Bytes to escape besides those the receiver asks for in ZRINIT.
+    /// Escaping all controls and the 8th bit is passed on to the receiver in
+    /// ZSINIT, telnet escaping is ours only. The 8th bit is escaped only for
+    /// receivers advertising XESC8, others get it as is.
+    pub escape: Escape,
+
+    /// Halve the subpacket size on every ZRPOS and double it back up to
+    /// `block_size` after a run of subpackets without errors
+    pub adaptive: bool,
+
+    /// Stop sending data on XOFF from the line until XON, for modems and
+    /// terminal servers doing software flow control. Sending resumes
+    /// after `timeout` if XON doesn't come.
+    pub xon_xoff: bool,
+
+    /// Time to wait for a reply of the receiver before sending again
+    pub timeout: Duration,
+
+    /// Compression of file data, used only if the receiver advertises it
+    /// in ZRINIT. Receivers like lrzsz that don't get plain data.
+    pub transport: Transport,
+
+    /// Identifies the remote in the session span of the `tracing`
+    /// feature and labels the metrics of the `metrics` feature, e.g. a
+    /// port name
+    pub peer: String,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            block_size: SUBPACKET_SIZE,
+            window:     SUBPACKET_SIZE * SUBPACKET_PER_ACK,
+            crc16:      false,
+            escape:     Escape::MINIMAL,
+            adaptive:   false,
+            xon_xoff:   false,
+            timeout:    TIMEOUT,
+            transport:  Transport::None,
+            peer:       String::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Subpackets of a byte at least and a window of a subpacket at least,
+    /// less never sends anything
+    fn clamped(&self) -> Options {
+        let block_size = self.block_size.max(1);
+        Options {
+            block_size,
+            window: self.window.max(block_size),
+            ..self.clone()
+        }
+    }
+}
+
+/// Protocol used by `send_auto`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Zmodem,
+    Ymodem,
+    /// Only the first file of the batch is sent
+    Xmodem,
+}
+
+/// Statistics of a finished transfer
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Statistics {
+    pub protocol: Protocol,
+    /// Files sent completely
+    pub files: u32,
+    /// File data sent in bytes, including retransmissions
+    pub bytes: u64,
+    /// Requests of the receiver to resend data (ZRPOS)
+    pub rewinds: u32,
+    /// Smallest data subpacket sent, excluding the last one of a file
+    pub min_block_size: usize,
+    /// Largest data subpacket sent
+    pub max_block_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum State {
     /// Waiting ZRINIT invite (do nothing)
     WaitingInit,
@@ -18,6 +129,9 @@ enum State {
     /// Sending ZRQINIT
     SendingZRQINIT,
 
+    /// Sending ZSINIT, waiting for ZACK
+    SendingZSINIT,
+
     /// Sending ZFILE frame
     SendingZFILE,
 
@@ -27,6 +141,9 @@ enum State {
     /// Sending ZDATA & subpackets
     SendingData,
 
+    /// Sent ZEOF, waiting for ZRINIT
+    SendingZEOF,
+
     /// Sending ZFIN
     SendingZFIN,
 
@@ -39,99 +156,317 @@ impl State {
         State::WaitingInit
     }
 
-    fn next(self, frame: &Frame) -> State {
-        match (self, frame.get_frame_type()) {
+    /// Unexpected frames leave the state unchanged
+    fn next(self, frame: &Frame) -> result::Result<State, ProtocolError> {
+        Ok(match (self, frame.get_frame_type()) {
             (State::WaitingInit,  ZRINIT)   => State::SendingZFILE,
             (State::WaitingInit,  _)        => State::SendingZRQINIT,
 
             (State::SendingZRQINIT, ZRINIT) => State::SendingZFILE,
 
+            (State::SendingZSINIT, ZACK)    => State::SendingZFILE,
+            (State::SendingZSINIT, ZNAK)    => State::SendingZSINIT,
+            (State::SendingZSINIT, ZRINIT)  => State::SendingZFILE, // ZSINIT ignored
+
             (State::SendingZFILE, ZRPOS)    => State::SendingData,
             (State::SendingZFILE, ZRINIT)   => State::WaitingZPOS,
+            (State::SendingZFILE, ZNAK)     => State::SendingZFILE,
+            (State::SendingZFILE, ZSKIP)    => State::SendingZFILE,
+            (State::SendingZFILE, ZACK)     => State::WaitingZPOS, // late reply to ZSINIT
 
             (State::WaitingZPOS, ZRPOS)     => State::SendingData,
+            (State::WaitingZPOS, ZRINIT)    => State::SendingZFILE, // ZFILE got lost
+            (State::WaitingZPOS, ZSKIP)     => State::SendingZFILE,
 
-            (State::SendingData,  ZACK)     => State::SendingData,
             (State::SendingData,  ZRPOS)    => State::SendingData,
-            (State::SendingData,  ZRINIT)   => State::SendingZFIN,
+            (State::SendingData,  ZRINIT)   => State::SendingZFILE,
+            (State::SendingData,  ZSKIP)    => State::SendingZFILE,
 
-            (State::SendingZFIN,  ZFIN)     => State::Done,
+            (State::SendingZEOF,  ZACK)     => State::SendingZEOF, // late reply to ZCRCQ
+            (State::SendingZEOF,  ZRPOS)    => State::SendingData,
+            (State::SendingZEOF,  ZRINIT)   => State::SendingZFILE,
+            (State::SendingZEOF,  ZSKIP)    => State::SendingZFILE,
 
-            (s, _) => {
-               error!("Unexpected (state, frame) combination: {:#?} {}", s, frame);
-               s // don't change current state
-            },
-        }
+            (State::SendingZFIN,  ZFIN)     => State::Done,
+            (State::SendingZFIN,  ZRINIT)   => State::SendingZFIN, // ZFIN got lost
+            (State::SendingZFIN,  ZNAK)     => State::SendingZFIN,
+
+            (s, _) => return Err(ProtocolError::UnexpectedFrame {
+                state: format!("{:?}", s),
+                frame: frame.get_frame_type(),
+            }),
+        })
     }
 }
 
+/// Sends one file by Z-Modem protocol
 pub async fn send<RW, R>(rw: RW, r: &mut R, filename: &str, filesize: Option<u32>) -> Result<RW>
     where RW: AsyncRead + AsyncWrite + Unpin,
           R:  AsyncRead + AsyncSeek + Unpin
 {
+    let info = FileInfo {
+        size: filesize,
+        ..FileInfo::new(filename)
+    };
+
+    let (rw, _) = send_files(rw, [(info, r)], &Options::default()).await?;
+    Ok(rw)
+}
+
+/// Sends a batch of files by Z-Modem protocol.
+/// Files are taken from `files` one by one as the receiver asks for them.
+pub async fn send_files<RW, R, I>(rw: RW, files: I, options: &Options) -> Result<(RW, Statistics)>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + AsyncSeek + Unpin,
+          I:  IntoIterator<Item = (FileInfo, R)>
+{
+    instrument::session(send_session(rw, files, options, false), "send", &options.peer).await
+}
+
+/// Sends a batch of files like `send_files`, but falls back to YMODEM if
+/// the receiver asks for it with 'C' or 'G' instead of answering ZRQINIT,
+/// and to XMODEM if it asks for checksums or ignores YMODEM block 0.
+/// `Statistics::protocol` tells which one was used.
+pub async fn send_auto<RW, R, I>(rw: RW, files: I, options: &Options) -> Result<(RW, Statistics)>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + AsyncSeek + Unpin,
+          I:  IntoIterator<Item = (FileInfo, R)>
+{
+    instrument::session(send_session(rw, files, options, true), "send", &options.peer).await
+}
+
+/// Sends a batch of files from readers that can't seek, e.g. pipes or
+/// sockets. The last `window` bytes sent are kept for retransmission,
+/// the transfer is aborted if the receiver asks to rewind further.
+/// Leave `FileInfo::size` empty if it isn't known.
+pub async fn send_stream<RW, R, I>(rw: RW, files: I, options: &Options) -> Result<(RW, Statistics)>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin,
+          I:  IntoIterator<Item = (FileInfo, R)>
+{
+    let options = &options.clamped();
+    let files = Rewinding {
+        files:    files.into_iter(),
+        capacity: options.window + options.block_size,
+    };
+
+    instrument::session(send_session(rw, files, options, false), "send", &options.peer).await
+}
+
+/// Wraps the readers of a batch into `Rewind`.
+/// Not a closure, which would make the future of `send_stream` not `Send`.
+struct Rewinding<I> {
+    files:    I,
+    capacity: usize,
+}
+
+impl<R: AsyncRead, I: Iterator<Item = (FileInfo, R)>> Iterator for Rewinding<I> {
+    type Item = (FileInfo, Rewind<R>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.files.next().map(|(info, r)| (info, Rewind::new(r, self.capacity)))
+    }
+}
+
+async fn send_session<RW, R, I>(rw: RW, files: I, options: &Options, fallback: bool) -> Result<(RW, Statistics)>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + AsyncSeek + Unpin,
+          I:  IntoIterator<Item = (FileInfo, R)>
+{
+    let options = &options.clamped();
     let mut rw_log = rwlog::ReadWriteLog::new(rw);
 
-    let mut data = [0; SUBPACKET_SIZE];
-    let mut offset: u32;
+    let mut files = files.into_iter();
+    let mut file = None;
+    let mut data = vec![0; options.block_size];
+    let mut window = Window {
+        size:     options.window,
+        overlap:  true,
+        xon_xoff: options.xon_xoff,
+        timeout:  options.timeout,
+    };
+    let mut enc = Encoding {
+        header:     if options.crc16 { ZBIN } else { ZBIN32 },
+        escape:     options.escape,
+        transport:  Transport::None,
+    };
 
     write_zrqinit(&mut rw_log).await?;
 
     let mut state = State::new();
+    let mut pending = None;
+    // offset of the last ZEOF
+    let mut eof = 0;
+    let mut stats = Statistics::default();
+    let mut block = BlockSize::new(options);
+    let mut unexpected = 0;
+    let mut damaged = 0;
+    let mut timeouts = 0;
+    // garbage doesn't put off the timeout, only headers do
+    let mut deadline = Instant::now() + options.timeout;
 
     while state != State::Done {
-        rw_log.flush().await?;
+        let frame = match pending.take() {
+            Some(x) => x,
+            None    => {
+                rw_log.flush().await?;
+
+                let initiators = fallback && matches!(state, State::WaitingInit | State::SendingZRQINIT);
+                let reply = match timeout_at(deadline, recv_init_reply(&mut rw_log, initiators)).await {
+                    Ok(x)  => { timeouts = 0; x? },
+                    Err(_) => {
+                        error!("timeout waiting for a frame in state {:?}", state);
+                        event!(warn, state = ?state, "timeout");
+                        instrument::count("zmodem_timeouts_total", 1);
+                        timeouts += 1;
+                        if timeouts >= MAX_ERRORS {
+                            return Err(ProtocolError::Timeout.into());
+                        }
+                        deadline = Instant::now() + options.timeout;
+                        resend(&mut rw_log, state, enc, options, &file, eof).await?;
+                        continue;
+                    },
+                };
+
+                match reply {
+                    Reply::Garbage         => continue,
+                    Reply::Initiator(check) => {
+                        debug!("falling back to YMODEM, receiver asks for {:?}", check);
+
+                        let stats = ymodem::send_batch(&mut rw_log, files, options, Some(check), true).await?;
+                        return Ok((rw_log.into_inner(), stats));
+                    },
+                    Reply::Damaged         => {
+                        deadline = Instant::now() + options.timeout;
+                        damaged += 1;
+                        if damaged >= MAX_ERRORS {
+                            error!("too many damaged headers");
+                            return Err(ProtocolError::TooManyErrors.into());
+                        }
+                        write_znak(&mut rw_log).await?;
+                        continue;
+                    },
+                    Reply::Frame(x)        => {
+                        deadline = Instant::now() + options.timeout;
+                        damaged = 0;
+                        x
+                    },
+                }
+            },
+        };
 
-        if !find_zpad(&mut rw_log).await? {
-            continue;
+        if matches!(frame.get_frame_type(), ZABORT | ZFERR | ZCAN) {
+            error!("receiver aborted the transfer: {}", frame);
+            return Err(ProtocolError::RemoteAborted.into());
         }
 
-        let frame = match parse_header(&mut rw_log).await? {
-            Some(x) => x,
-            None    => { write_znak(&mut rw_log).await?; continue },
+        let prev = state;
+        state = match state.next(&frame) {
+            Ok(x)  => { unexpected = 0; x },
+            Err(e) => {
+                error!("{}", e);
+                event!(warn, error = %e, "unexpected frame");
+                unexpected += 1;
+                if unexpected >= MAX_ERRORS {
+                    return Err(e.into());
+                }
+                // its count isn't an offset to resend from
+                continue;
+            },
         };
-
-        state = state.next(&frame);
         debug!("State: {:?}", state);
+        event!(debug, frame = frame.type_name(), state = ?state, "state");
+
+        match (prev, frame.get_frame_type()) {
+            (State::SendingData | State::SendingZEOF, ZRPOS) => {
+                stats.rewinds += 1;
+                instrument::count("zmodem_rewinds_total", 1);
+                block.shrink();
+            },
+            (State::SendingZEOF, ZRINIT) => stats.files += 1,
+            _ => (),
+        }
+
+        if frame.get_frame_type() == ZRINIT && !matches!(prev, State::SendingData | State::SendingZEOF) {
+            let flags = frame.get_flags();
+            let rx_buf = (flags[ZP0] as usize) | (flags[ZP1] as usize) << 8;
+
+            if flags[ZF0] & CANFC32 == 0 {
+                enc.header = ZBIN;
+            }
+            if rx_buf != 0 {
+                window.size = window.size.min(rx_buf);
+                block.limit(rx_buf);
+            }
+            window.overlap = flags[ZF0] & (CANFDX | CANOVIO) == CANFDX | CANOVIO;
+            enc.transport = if options.transport.is_advertised(&flags) { options.transport } else { Transport::None };
+            // ESC_8 is our extension, only receivers asking for it decode it
+            let mut escape = options.escape;
+            if escape.eight_bit && flags[ZF1] & XESC8 == 0 {
+                warn!("receiver doesn't decode ESC_8, sending the 8th bit as is");
+                escape.eight_bit = false;
+            }
+            enc.escape = escape.union(Escape::from_flags(&flags));
+
+            // the receiver escapes its binary frames as we ask in ZSINIT
+            if matches!(prev, State::WaitingInit | State::SendingZRQINIT) && options.escape.is_negotiated() {
+                state = State::SendingZSINIT;
+            }
+        }
 
         // do things according new state
         match state {
             State::SendingZRQINIT => {
                 write_zrqinit(&mut rw_log).await?;
             },
+            State::SendingZSINIT => {
+                write_zsinit(&mut rw_log, enc, options.escape).await?;
+            },
             State::SendingZFILE => {
-                write_zfile(&mut rw_log, filename, filesize).await?;
+                // repeat the current file only if the receiver didn't get it
+                if !matches!(prev, State::SendingZFILE | State::WaitingZPOS) || frame.get_frame_type() == ZSKIP {
+                    file = files.next();
+                }
+
+                match file {
+                    Some((ref info, _)) => {
+                        instrument::file(&info.name);
+                        write_zfile(&mut rw_log, enc, info).await?;
+                    },
+                    None                => {
+                        state = State::SendingZFIN;
+                        write_zfin(&mut rw_log).await?;
+                    },
+                }
             },
             State::SendingData  => {
-                offset = frame.get_count();
-                r.seek(SeekFrom::Start(offset as u64)).await?;
-
-                let num = r.read(&mut data).await?;
-
-                if num == 0 {
-                    write_zeof(&mut rw_log, offset).await?;
+                let r = match file {
+                    Some((_, ref mut r)) => r,
+                    None                 => continue,
+                };
+
+                // streams end before the offset instead of failing
+                let offset = frame.get_count();
+                let error = match r.seek(SeekFrom::Start(offset as u64)).await {
+                    Ok(x) if x == offset as u64 => None,
+                    Ok(x)  => Some(ProtocolError::OffsetMismatch { expected: offset, actual: x as u32 }.into()),
+                    Err(e) => Some(ZmodemError::from(e)),
+                };
+                if let Some(e) = error {
+                    error!("can't seek to {}: {}", offset, e);
+                    write_zferr(&mut rw_log).await?;
+                    write_cancel(&mut rw_log).await?;
+                    return Err(e);
                 }
-                else {
-                    // ZBIN32|ZDATA
-                    // ZCRCG - best perf
-                    // ZCRCQ - mid perf
-                    // ZCRCW - worst perf
-                    // ZCRCE - send at end
-                    write_zdata(&mut rw_log, offset).await?;
-
-                    let mut i = 0;
-                    loop {
-                        i += 1;
-
-                        write_zlde_data(&mut rw_log, ZCRCG, &data[..num]).await?;
-                        offset += num as u32;
-
-                        let num = r.read(&mut data).await?;
-                        if num < data.len() || i >= SUBPACKET_PER_ACK {
-                            write_zlde_data(&mut rw_log, ZCRCW, &data[..num]).await?;
-                            break;
-                        }
-                    }
+
+                match send_burst(&mut rw_log, r, enc, offset, &mut data, window, &mut block, &mut stats).await? {
+                    Burst::Interrupted(frame) => pending = Some(frame),
+                    Burst::Ended(offset)      => {
+                        eof = offset;
+                        state = State::SendingZEOF;
+                    },
                 }
+                deadline = Instant::now() + options.timeout;
             },
             State::SendingZFIN  => {
                 write_zfin(&mut rw_log).await?;
@@ -143,6 +478,355 @@ pub async fn send<RW, R>(rw: RW, r: &mut R, filename: &str, filesize: Option<u32
         }
     }
 
-    Ok(rw_log.into_inner())
+    Ok((rw_log.into_inner(), stats))
+}
+
+/// What the receiver sent while the sender waits for a header
+enum Reply {
+    Frame(Frame),
+    Damaged,
+    /// XMODEM or YMODEM receiver asking for data instead of sending ZRINIT
+    Initiator(Check),
+    Garbage,
 }
 
+/// Reads up to the next header, `initiators` looks for XMODEM and YMODEM
+/// receivers as well
+async fn recv_init_reply<R>(r: &mut R, initiators: bool) -> Result<Reply>
+    where R: AsyncBufRead + Unpin {
+
+    if initiators {
+        let b = r.fill_buf().await?.first().copied();
+
+        if let Some(check) = b.and_then(Check::from_initiator) {
+            r.consume(1);
+            return Ok(Reply::Initiator(check));
+        }
+    }
+
+    if !find_zpad(r).await? {
+        return Ok(Reply::Garbage);
+    }
+
+    Ok(match or_damaged(parse_header(r).await)? {
+        Some(x) => Reply::Frame(x),
+        None    => Reply::Damaged,
+    })
+}
+
+/// Sends the last frame of `state` again after its reply didn't come
+async fn resend<W, R>(w: &mut W, state: State, enc: Encoding, options: &Options, file: &Option<(FileInfo, R)>, eof: u32) -> Result<()>
+    where W: AsyncWrite + Unpin {
+
+    match (state, file) {
+        (State::WaitingInit | State::SendingZRQINIT, _)        => write_zrqinit(w).await,
+        (State::SendingZSINIT, _)                              => write_zsinit(w, enc, options.escape).await,
+        (State::SendingZFILE | State::WaitingZPOS, Some((info, _))) => write_zfile(w, enc, info).await,
+        (State::SendingZEOF, _)                                => write_zeof(w, enc, eof).await,
+        (State::SendingZFIN, _)                                => write_zfin(w).await,
+        _                                                      => Ok(()),
+    }
+}
+
+/// How data sent by `send_burst` ended
+enum Burst {
+    /// At the end of file, at this offset
+    Ended(u32),
+    /// By a frame of the receiver other than ZACK
+    Interrupted(Frame),
+}
+
+/// Flow control parameters negotiated with the receiver
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    /// Unacknowledged bytes allowed in flight
+    size: usize,
+
+    /// The receiver reads while writing to disk (CANFDX and CANOVIO),
+    /// otherwise it gets ZCRCW and a pause at the end of every window
+    overlap: bool,
+
+    /// XOFF from the line pauses sending until XON
+    xon_xoff: bool,
+
+    /// Time to wait for ZACK and XON
+    timeout: Duration,
+}
+
+/// Sends data from `offset` up to the end of file and ZEOF, reading
+/// replies between subpackets. ZCRCQ asks for ZACK every quarter of the
+/// window, sending stops only when the window is full.
+#[allow(clippy::too_many_arguments)]
+async fn send_burst<RW, R>(rw: &mut RW, r: &mut R, enc: Encoding, offset: u32, data: &mut [u8], window: Window,
+                           block: &mut BlockSize, stats: &mut Statistics) -> Result<Burst>
+    where RW: AsyncBufRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin {
+
+    let spacing = (window.size / 4).max(block.max) as u32;
+    let window_size = window.size as u32;
+    let mut sent = offset;
+    let mut acked = offset;
+    let mut ack_requested = offset;
+    let mut cans = 0;
+    let mut xoff = false;
+    let mut timeouts = 0;
+
+    write_zdata(rw, enc, offset).await?;
+
+    loop {
+        // replies that have already arrived
+        while has_input(rw).await? {
+            match recv_burst_reply(rw, &mut cans, &mut xoff).await? {
+                Some(frame) if frame.get_frame_type() == ZACK => acked = ack(acked, sent, &frame),
+                Some(frame)                                   => return end_burst(rw, enc, frame).await,
+                None                                          => (),
+            }
+        }
+
+        // the window is full, wait for the reply to the last ZCRCQ
+        while sent - acked >= window_size {
+            rw.flush().await?;
+            match timeout(window.timeout, recv_burst_reply(rw, &mut cans, &mut xoff)).await {
+                Ok(x) => match x? {
+                    Some(frame) if frame.get_frame_type() == ZACK => { timeouts = 0; acked = ack(acked, sent, &frame) },
+                    Some(frame)                                   => return end_burst(rw, enc, frame).await,
+                    None                                          => (),
+                },
+                Err(_) => acked = ack_timeout(sent, &mut timeouts)?,
+            }
+        }
+
+        // the line asked for a pause
+        while xoff && window.xon_xoff {
+            rw.flush().await?;
+            match timeout(window.timeout, recv_burst_reply(rw, &mut cans, &mut xoff)).await {
+                Ok(x) => match x? {
+                    Some(frame) if frame.get_frame_type() == ZACK => acked = ack(acked, sent, &frame),
+                    Some(frame)                                   => return end_burst(rw, enc, frame).await,
+                    None                                          => (),
+                },
+                Err(_) => {
+                    warn!("no XON after XOFF, resuming");
+                    xoff = false;
+                },
+            }
+        }
+
+        let len = block.current;
+        let num = read_block(r, &mut data[..len]).await?;
+        let end = sent + num as u32;
+
+        // ZCRCE ends the data, ZCRCW waits for ZACK, ZCRCQ asks for it
+        // on the fly and ZCRCG just goes on
+        let zcrc =
+            if num < len                                         { ZCRCE }
+            else if !window.overlap && end - acked >= window_size { ZCRCW }
+            else if end - ack_requested >= spacing || end - acked >= window_size { ZCRCQ }
+            else                                                 { ZCRCG };
+
+        write_zlde_data(rw, enc, zcrc, &enc.transport.compress(&data[..num])).await?;
+        sent = end;
+        stats.bytes += num as u64;
+        stats.max_block_size = stats.max_block_size.max(num);
+        if num == len {
+            stats.min_block_size = match stats.min_block_size {
+                0 => num,
+                x => x.min(num),
+            };
+        }
+        block.clean();
+
+        match zcrc {
+            ZCRCE => {
+                write_zeof(rw, enc, sent).await?;
+                return Ok(Burst::Ended(sent));
+            },
+            ZCRCQ => ack_requested = sent,
+            ZCRCW => {
+                // the receiver doesn't listen until it's written the data
+                while acked < sent {
+                    rw.flush().await?;
+                    match timeout(window.timeout, recv_burst_reply(rw, &mut cans, &mut xoff)).await {
+                        Ok(x) => match x? {
+                            Some(frame) if frame.get_frame_type() == ZACK => { timeouts = 0; acked = ack(acked, sent, &frame) },
+                            Some(frame)                                   => return Ok(Burst::Interrupted(frame)),
+                            None                                          => (),
+                        },
+                        Err(_) => acked = ack_timeout(sent, &mut timeouts)?,
+                    }
+                }
+                ack_requested = sent;
+                write_zdata(rw, enc, sent).await?;
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Closes the data frame with an empty ZCRCE subpacket, so the receiver
+/// expects a header, and passes on the frame that interrupted it
+async fn end_burst<W>(w: &mut W, enc: Encoding, frame: Frame) -> Result<Burst>
+    where W: AsyncWrite + Unpin {
+
+    debug!("data interrupted by {}", frame);
+    write_zlde_data(w, enc, ZCRCE, &[]).await?;
+    Ok(Burst::Interrupted(frame))
+}
+
+/// Subpacket size, adaptive or fixed
+#[derive(Debug)]
+struct BlockSize {
+    current:  usize,
+    max:      usize,
+    adaptive: bool,
+    clean:    u32,
+}
+
+impl BlockSize {
+    fn new(options: &Options) -> BlockSize {
+        BlockSize {
+            current:  options.block_size,
+            max:      options.block_size,
+            adaptive: options.adaptive,
+            clean:    0,
+        }
+    }
+
+    /// The receiver can't take subpackets longer than `len`
+    fn limit(&mut self, len: usize) {
+        self.max = self.max.min(len);
+        self.current = self.current.min(len);
+    }
+
+    /// The receiver got a damaged subpacket
+    fn shrink(&mut self) {
+        self.clean = 0;
+        if self.adaptive && self.current > MIN_SUBPACKET_SIZE {
+            self.current = (self.current / 2).max(MIN_SUBPACKET_SIZE);
+            debug!("subpacket size decreased to {}", self.current);
+        }
+    }
+
+    /// A subpacket is sent, grow after enough of them
+    fn clean(&mut self) {
+        self.clean += 1;
+        if self.adaptive && self.clean >= CLEAN_RUN && self.current < self.max {
+            self.clean = 0;
+            self.current = (self.current * 2).min(self.max);
+            debug!("subpacket size increased to {}", self.current);
+        }
+    }
+}
+
+/// Acknowledged offset after ZACK, ignoring bogus ones
+fn ack(acked: u32, sent: u32, frame: &Frame) -> u32 {
+    match frame.get_count() {
+        x if x <= sent => acked.max(x),
+        x              => {
+            error!("ZACK offset {} beyond sent data {}", x, sent);
+            acked
+        },
+    }
+}
+
+/// Takes `sent` as acknowledged when ZACK doesn't come, the receiver asks
+/// with ZRPOS for data it didn't get. Fails after MAX_ERRORS in a row.
+fn ack_timeout(sent: u32, timeouts: &mut usize) -> Result<u32> {
+    warn!("no ZACK up to {}, going on", sent);
+    instrument::count("zmodem_timeouts_total", 1);
+    *timeouts += 1;
+    if *timeouts >= MAX_ERRORS {
+        return Err(ProtocolError::Timeout.into());
+    }
+    Ok(sent)
+}
+
+/// Reads a reply of the receiver, None for garbage and damaged headers.
+/// Five CANs in a row abort the transfer. XOFF and XON set `xoff`.
+async fn recv_reply<R>(r: &mut R, cans: &mut usize, xoff: &mut bool) -> Result<Option<Frame>>
+    where R: AsyncBufRead + Unpin {
+
+    if r.fill_buf().await?.first() == Some(&ZPAD) {
+        *cans = 0;
+        return match find_zpad(r).await? {
+            true  => or_damaged(parse_header(r).await),
+            false => Ok(None),
+        };
+    }
+
+    match read_byte(r).await? {
+        CAN => {
+            *cans += 1;
+            match *cans {
+                5.. => Err(ProtocolError::Cancelled.into()),
+                _   => Ok(None),
+            }
+        },
+        x if is_flow_control(x) => {
+            *xoff = x & 0x7f == XOFF;
+            Ok(None)
+        },
+        _   => {
+            *cans = 0;
+            Ok(None)
+        },
+    }
+}
+
+/// Reads a reply during data, ignoring frames that don't concern it
+/// instead of interrupting the data for them
+async fn recv_burst_reply<R>(r: &mut R, cans: &mut usize, xoff: &mut bool) -> Result<Option<Frame>>
+    where R: AsyncBufRead + Unpin {
+
+    Ok(recv_reply(r, cans, xoff).await?.filter(|frame| {
+        let stray = !matches!(frame.get_frame_type(), ZACK | ZABORT | ZFERR | ZCAN) && State::SendingData.next(frame).is_err();
+        if stray {
+            warn!("ignoring {} during data", frame);
+        }
+        !stray
+    }))
+}
+
+/// Fills `buf` unless the end of file is reached, returns number of bytes read
+pub(crate) async fn read_block<R>(r: &mut R, buf: &mut [u8]) -> Result<usize>
+    where R: AsyncRead + Unpin {
+
+    let mut num = 0;
+    while num < buf.len() {
+        match r.read(&mut buf[num..]).await? {
+            0 => break,
+            n => num += n,
+        }
+    }
+
+    Ok(num)
+}
+
+#[test]
+fn test_block_size() {
+    let options = Options {
+        block_size: 1024,
+        adaptive:   true,
+        ..Default::default()
+    };
+    let mut block = BlockSize::new(&options);
+
+    for _ in 0..10 {
+        block.shrink();
+    }
+    assert_eq!(block.current, MIN_SUBPACKET_SIZE);
+
+    for _ in 0..CLEAN_RUN {
+        block.clean();
+    }
+    assert_eq!(block.current, MIN_SUBPACKET_SIZE * 2);
+
+    for _ in 0..CLEAN_RUN * 10 {
+        block.clean();
+    }
+    assert_eq!(block.current, 1024);
+
+    let mut block = BlockSize::new(&Options { adaptive: false, ..options });
+    block.shrink();
+    assert_eq!(block.current, 1024);
+}
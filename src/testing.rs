@@ -0,0 +1,229 @@
+//! In-memory link between two ends of a session that damages data like a
+//! bad serial line or modem connection, to exercise error recovery in tests.
+//! The damage is drawn from a seeded generator so failures can be reproduced.
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+use crate::consts::*;
+
+/// Bytes written but not read yet before writing waits
+const CAPACITY: usize = 16 * 1024;
+
+/// Damage done to the data sent in one direction. Probabilities apply to
+/// every byte written; the default is a perfect line.
+#[derive(Debug, Clone, Default)]
+pub struct Impairments {
+    /// Probability of flipping a random bit
+    pub bit_flips: f64,
+
+    /// Probability of losing the byte
+    pub drops: f64,
+
+    /// Probability of receiving the byte twice
+    pub dups: f64,
+
+    /// Probability of a random byte arriving before it
+    pub noise: f64,
+
+    /// Probability of XON or XOFF arriving before it, as inserted by
+    /// modems and terminal servers doing software flow control
+    pub xon_xoff: f64,
+
+    /// Time from leaving the sender to arriving at the receiver
+    pub latency: Duration,
+
+    /// Bytes per second, unlimited if None
+    pub bandwidth: Option<u32>,
+}
+
+/// Creates both ends of a link. `a_to_b` damages data written to the first
+/// end, `b_to_a` data written to the second one.
+pub fn link(a_to_b: Impairments, b_to_a: Impairments, seed: u64) -> (Link, Link) {
+    let a_to_b = Arc::new(Mutex::new(Line::new(a_to_b, seed, 0)));
+    let b_to_a = Arc::new(Mutex::new(Line::new(b_to_a, seed, 1)));
+
+    let a = Link { tx: a_to_b.clone(), rx: b_to_a.clone(), sleep: None };
+    let b = Link { tx: b_to_a, rx: a_to_b, sleep: None };
+    (a, b)
+}
+
+/// End of a link created by `link`. Reads return EOF once the other end is
+/// dropped or shut down and everything it wrote has arrived.
+pub struct Link {
+    tx:    Arc<Mutex<Line>>,
+    rx:    Arc<Mutex<Line>>,
+    /// Waits for the next data to arrive
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// One direction of a link
+struct Line {
+    impairments: Impairments,
+    rng:         XorShiftRng,
+    /// Damaged data with the time it arrives
+    queue:       VecDeque<(Instant, Vec<u8>)>,
+    queued:      usize,
+    /// When the line is done transmitting the data written so far
+    free_at:     Instant,
+    reader:      Option<Waker>,
+    writer:      Option<Waker>,
+    closed:      bool,
+}
+
+impl Line {
+    fn new(impairments: Impairments, seed: u64, direction: u32) -> Line {
+        // the constant keeps the seed from being all zeros
+        let seed = [seed as u32, (seed >> 32) as u32, direction, 0x9e37_79b9];
+
+        Line {
+            impairments,
+            rng:     XorShiftRng::from_seed(seed),
+            queue:   VecDeque::new(),
+            queued:  0,
+            free_at: Instant::now(),
+            reader:  None,
+            writer:  None,
+            closed:  false,
+        }
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.next_f64() < p
+    }
+
+    /// Applies the impairments to written data
+    fn impair(&mut self, data: &[u8]) -> Vec<u8> {
+        let x = self.impairments.clone();
+        let mut out = Vec::with_capacity(data.len());
+
+        for &b in data {
+            if self.chance(x.noise) {
+                out.push(self.rng.gen());
+            }
+            if self.chance(x.xon_xoff) {
+                out.push(if self.rng.gen() { XON } else { XOFF });
+            }
+            if self.chance(x.drops) {
+                continue;
+            }
+
+            let b = if self.chance(x.bit_flips) { b ^ 1 << self.rng.gen_range(0, 8) } else { b };
+            out.push(b);
+            if self.chance(x.dups) {
+                out.push(b);
+            }
+        }
+
+        out
+    }
+
+    fn push(&mut self, data: Vec<u8>) {
+        let transmit = match self.impairments.bandwidth {
+            Some(x) => Duration::from_secs_f64(data.len() as f64 / x.max(1) as f64),
+            None    => Duration::ZERO,
+        };
+        self.free_at = self.free_at.max(Instant::now()) + transmit;
+
+        self.queued += data.len();
+        self.queue.push_back((self.free_at + self.impairments.latency, data));
+        wake(&mut self.reader);
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        wake(&mut self.reader);
+        wake(&mut self.writer);
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(x) = waker.take() {
+        x.wake();
+    }
+}
+
+impl AsyncRead for Link {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            let mut line = this.rx.lock().unwrap();
+
+            let arrival = match line.queue.front() {
+                Some(&(x, _)) => x,
+                None if line.closed => return Poll::Ready(Ok(())),
+                None => {
+                    line.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                },
+            };
+
+            if arrival > Instant::now() {
+                drop(line);
+
+                let sleep = this.sleep.get_or_insert_with(|| Box::pin(sleep_until(arrival)));
+                sleep.as_mut().reset(arrival);
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                continue;
+            }
+
+            let (_, data) = line.queue.front_mut().unwrap();
+            let n = data.len().min(buf.remaining());
+            buf.put_slice(&data[..n]);
+            data.drain(..n);
+            if data.is_empty() {
+                line.queue.pop_front();
+            }
+
+            line.queued -= n;
+            wake(&mut line.writer);
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl AsyncWrite for Link {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut line = self.tx.lock().unwrap();
+
+        if line.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if line.queued >= CAPACITY {
+            line.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(CAPACITY - line.queued);
+        let data = line.impair(&buf[..n]);
+        line.push(data);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.tx.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.tx.lock().unwrap().close();
+        self.rx.lock().unwrap().close();
+    }
+}
@@ -0,0 +1,346 @@
+//! Transport options of ZFILE (ZF2, ZF3 for extensions): compression of
+//! data subpackets.
+//! Every subpacket is compressed on its own, so the receiver can resume at
+//! any of them, and offsets exchanged in ZRPOS, ZACK, ZDATA and ZEOF are
+//! always counted in uncompressed bytes.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::consts::*;
+use crate::error::{ProtocolError, Result};
+
+/// Escape of ZTRLE subpackets, as in rzsz
+const ZRESC: u8 = 0x7e;
+/// ZRESC followed by this stands for ZRESC itself
+const ZRESC_LITERAL: u8 = 0x40;
+/// ZRESC followed by this plus the length stands for a run of spaces
+const RUN_SPACES: u8 = 0x1d;
+/// ZRESC followed by this plus the length and a byte stands for a run of it
+const RUN: u8 = 0x40;
+const MAX_SPACES: usize = 34;
+const MAX_RUN: usize = 127;
+/// Shortest run worth encoding, shorter ones are sent as they are
+const MIN_RUN: usize = 3;
+
+/// LZW codes 0-255 stand for bytes, the dictionary grows up to 12 bit codes
+const LZW_FIRST: u16 = 256;
+const LZW_CODES: u16 = 4096;
+
+/// Compression of the data subpackets of a file, chosen by the sender
+/// when the receiver advertises it can uncompress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// Subpackets carry the file data as it is
+    #[default]
+    None,
+    /// ZTRLE: runs of a byte are sent as ZRESC, a count and the byte,
+    /// in the format of rzsz
+    Rle,
+    /// LZW with codes of 9 to 12 bits packed from the low bit up, the
+    /// dictionary starts over with every subpacket. An extension of this
+    /// crate rather than ZTLZW: receivers advertise CANXLZW in ZF1 of
+    /// ZRINIT and ZFILE has ZXLZW in ZF3.
+    Lzw,
+}
+
+impl Transport {
+    /// Transport of the flags of ZFILE, None for those we can't uncompress
+    pub fn from_zfile(flags: &[u8; 4]) -> Option<Transport> {
+        match (flags[ZF2], flags[ZF3] & ZXLZW != 0) {
+            (0,     false) => Some(Transport::None),
+            (0,     true)  => Some(Transport::Lzw),
+            (ZTRLE, false) => Some(Transport::Rle),
+            // compress(1) data of rzsz, not our LZW
+            (ZTLZW, _)     => None,
+            _              => None,
+        }
+    }
+
+    /// Sets the flags of ZFILE
+    pub fn to_zfile(self, flags: &mut [u8; 4]) {
+        match self {
+            Transport::None => (),
+            Transport::Lzw  => flags[ZF3] |= ZXLZW,
+            Transport::Rle  => flags[ZF2] = ZTRLE,
+        }
+    }
+
+    /// Sets the flags of a ZRINIT advertising that we uncompress it
+    pub(crate) fn advertise(self, flags: &mut [u8; 4]) {
+        match self {
+            Transport::None => (),
+            Transport::Lzw  => flags[ZF1] |= CANXLZW,
+            Transport::Rle  => flags[ZF0] |= CANLZW,
+        }
+    }
+
+    /// The flags of the receiver's ZRINIT advertise that it uncompresses it
+    pub(crate) fn is_advertised(self, flags: &[u8; 4]) -> bool {
+        match self {
+            Transport::None => true,
+            Transport::Lzw  => flags[ZF1] & CANXLZW != 0,
+            Transport::Rle  => flags[ZF0] & CANLZW != 0,
+        }
+    }
+
+    /// Longest compressed subpacket of `len` bytes of data
+    pub fn max_len(self, len: usize) -> usize {
+        match self {
+            Transport::None => len,
+            // ZRESC takes two bytes, a run of them three; LZW codes of
+            // 12 bits at most stand for a byte at least
+            Transport::Rle | Transport::Lzw => len + len.div_ceil(2),
+        }
+    }
+
+    pub fn compress<'a>(self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Transport::None => Cow::Borrowed(data),
+            Transport::Rle  => Cow::Owned(rle_encode(data)),
+            Transport::Lzw  => Cow::Owned(lzw_encode(data)),
+        }
+    }
+
+    /// Uncompresses one verified subpacket of up to `max_len` bytes of data
+    pub fn uncompress<'a>(self, data: &'a [u8], max_len: usize) -> Result<Cow<'a, [u8]>> {
+        match self {
+            Transport::None if data.len() > max_len => Err(ProtocolError::SubpacketTooLong(max_len).into()),
+            Transport::None => Ok(Cow::Borrowed(data)),
+            Transport::Rle  => rle_decode(data, max_len).map(Cow::Owned),
+            Transport::Lzw  => lzw_decode(data, max_len).map(Cow::Owned),
+        }
+    }
+}
+
+/// Encodes runs of MIN_RUN and more bytes as ZRESC, RUN plus the length
+/// and the byte, runs of spaces as ZRESC and RUN_SPACES plus the length;
+/// ZRESC alone as ZRESC ZRESC_LITERAL
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+
+    while let Some(&x) = rest.first() {
+        let run = rest.iter().take(MAX_RUN).take_while(|&&y| y == x).count();
+
+        if x == b' ' && (MIN_RUN..=MAX_SPACES).contains(&run) {
+            out.extend_from_slice(&[ZRESC, RUN_SPACES + run as u8]);
+        }
+        else if run >= MIN_RUN || x == ZRESC && run > 1 {
+            out.extend_from_slice(&[ZRESC, RUN + run as u8, x]);
+        }
+        else if x == ZRESC {
+            out.extend_from_slice(&[ZRESC, ZRESC_LITERAL]);
+        }
+        else {
+            out.resize(out.len() + run, x);
+        }
+        rest = &rest[run..];
+    }
+
+    out
+}
+
+fn rle_decode(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut rest = data;
+
+    while let Some(&x) = rest.first() {
+        match rest {
+            [ZRESC, ZRESC_LITERAL, ..] => {
+                out.push(ZRESC);
+                rest = &rest[2..];
+            },
+            [ZRESC, n @ 0x20..=0x3f, ..] => {
+                out.resize(out.len() + (*n - RUN_SPACES) as usize, b' ');
+                rest = &rest[2..];
+            },
+            [ZRESC, n @ 0x41..=0xff, x, ..] => {
+                out.resize(out.len() + (*n - RUN) as usize, *x);
+                rest = &rest[3..];
+            },
+            [ZRESC, ..] => return Err(ProtocolError::BadCompressedData.into()),
+            _ => {
+                out.push(x);
+                rest = &rest[1..];
+            },
+        }
+        // a run may be longer than the rest of the limit
+        if out.len() > max_len {
+            return Err(ProtocolError::SubpacketTooLong(max_len).into());
+        }
+    }
+
+    Ok(out)
+}
+
+/// Width of the `i`th code of a subpacket. Both sides know the highest
+/// code that can occur, the encoder adds an entry after every code but
+/// the decoder only learns it with the next one.
+fn lzw_width(i: usize) -> u32 {
+    let max = (LZW_FIRST as usize - 1 + i).min(LZW_CODES as usize - 1);
+    (usize::BITS - max.leading_zeros()).max(9)
+}
+
+fn lzw_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut dict = HashMap::new();
+    let mut next = LZW_FIRST;
+    let (mut acc, mut bits, mut i) = (0u32, 0, 0);
+
+    let mut emit = |code: u16, out: &mut Vec<u8>| {
+        acc |= (code as u32) << bits;
+        bits += lzw_width(i);
+        i += 1;
+        while bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    };
+
+    let mut rest = data.iter();
+    let mut prefix = match rest.next() {
+        Some(&x) => x as u16,
+        None     => return out,
+    };
+
+    for &x in rest {
+        match dict.get(&(prefix, x)) {
+            Some(&code) => prefix = code,
+            None        => {
+                emit(prefix, &mut out);
+                if next < LZW_CODES {
+                    dict.insert((prefix, x), next);
+                    next += 1;
+                }
+                prefix = x as u16;
+            },
+        }
+    }
+    emit(prefix, &mut out);
+
+    if bits > 0 {
+        out.push(acc as u8);
+    }
+    out
+}
+
+fn lzw_decode(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    // entries from LZW_FIRST on, as where they start in `out` and length
+    let mut dict: Vec<(usize, usize)> = Vec::new();
+    let mut prev: Option<(usize, usize)> = None;
+    let (mut acc, mut bits, mut i) = (0u32, 0, 0);
+    let mut input = data.iter();
+
+    loop {
+        let width = lzw_width(i);
+        while bits < width {
+            match input.next() {
+                Some(&x) => {
+                    acc |= (x as u32) << bits;
+                    bits += 8;
+                },
+                // the rest is padding
+                None => return Ok(out),
+            }
+        }
+        let code = (acc & ((1 << width) - 1)) as usize;
+        acc >>= width;
+        bits -= width;
+        i += 1;
+
+        let start = out.len();
+        match code {
+            x if x < LZW_FIRST as usize => out.push(x as u8),
+            x if x - (LZW_FIRST as usize) < dict.len() => {
+                let (at, len) = dict[x - LZW_FIRST as usize];
+                out.extend_from_within(at..at + len);
+            },
+            // the entry the encoder added after the previous code
+            x if x - (LZW_FIRST as usize) == dict.len() && prev.is_some() => {
+                let (at, len) = prev.unwrap();
+                out.extend_from_within(at..at + len);
+                out.push(out[at]);
+            },
+            _ => return Err(ProtocolError::BadCompressedData.into()),
+        }
+        // a few codes of long entries stand for a lot of data
+        if out.len() > max_len {
+            return Err(ProtocolError::SubpacketTooLong(max_len).into());
+        }
+
+        if let Some((at, len)) = prev {
+            if LZW_FIRST as usize + dict.len() < LZW_CODES as usize {
+                dict.push((at, len + 1));
+            }
+        }
+        prev = Some((start, out.len() - start));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ZmodemError;
+
+    #[test]
+    fn test_rle() {
+        let data = [&[1, 2, 3][..], &[0xff; 1000], &[ZRESC, 4, 4, 5, 5, 5], &[ZRESC; 6], b"a    b"].concat();
+        let packed = Transport::Rle.compress(&data);
+        assert!(packed.len() < 50);
+        assert_eq!(&packed[..6], &[1, 2, 3, ZRESC, 0xbf, 0xff]);
+        assert_eq!(&packed[packed.len() - 14..], &[ZRESC, 0x40, 4, 4, ZRESC, 0x43, 5, ZRESC, 0x46, ZRESC, b'a', ZRESC, 0x21, b'b']);
+        assert_eq!(Transport::Rle.uncompress(&packed, data.len()).unwrap(), data);
+
+        // as rzsz sends them
+        assert_eq!(Transport::Rle.uncompress(&[ZRESC, 0x40, ZRESC, 0x3f, ZRESC, 0x42, ZRESC, ZRESC, 0xc1, 0], 1024).unwrap(),
+                   [&[ZRESC][..], &[b' '; 34], &[ZRESC; 2], &[0; 129]].concat());
+
+        // the worst case
+        let data = [ZRESC, 0].repeat(100);
+        let packed = Transport::Rle.compress(&data);
+        assert_eq!(packed.len(), Transport::Rle.max_len(data.len()));
+        assert_eq!(Transport::Rle.uncompress(&packed, data.len()).unwrap(), data);
+
+        assert!(Transport::Rle.uncompress(&[1, ZRESC], 1024).is_err());
+        assert!(Transport::Rle.uncompress(&[1, ZRESC, 0x50], 1024).is_err());
+        assert!(Transport::Rle.uncompress(&[1, ZRESC, 0x10, 0], 1024).is_err());
+
+        // runs beyond the limit
+        assert!(matches!(Transport::Rle.uncompress(&[ZRESC, 0xff, 0].repeat(10), 1024),
+                         Err(ZmodemError::ProtocolError(ProtocolError::SubpacketTooLong(1024)))));
+        assert!(Transport::None.uncompress(&[0; 1025], 1024).is_err());
+    }
+
+    #[test]
+    fn test_lzw() {
+        let text = b"2024-05-01 12:00:00 INFO link up\n".repeat(200);
+        let packed = Transport::Lzw.compress(&text);
+        assert!(packed.len() < text.len() / 5);
+        assert_eq!(Transport::Lzw.uncompress(&packed, text.len()).unwrap(), text);
+
+        // the code for the entry being defined
+        let data = b"abababababa";
+        assert_eq!(Transport::Lzw.uncompress(&Transport::Lzw.compress(data), data.len()).unwrap(), &data[..]);
+
+        // codes grow to 12 bits and the dictionary fills up
+        let data = (0..8192u32).map(|x| (x * x % 251) as u8).collect::<Vec<_>>();
+        let packed = Transport::Lzw.compress(&data);
+        assert!(packed.len() <= Transport::Lzw.max_len(data.len()));
+        assert_eq!(Transport::Lzw.uncompress(&packed, data.len()).unwrap(), data);
+
+        assert!(Transport::Lzw.compress(&[]).is_empty());
+        assert_eq!(Transport::from_zfile(&[0, ZTLZW, 0, 0]), None);
+        assert_eq!(Transport::from_zfile(&[ZXLZW, 0, 0, 0]), Some(Transport::Lzw));
+        // 9 bit code 0x1ff before the dictionary has it
+        assert!(Transport::Lzw.uncompress(&[0xff, 0x01], 1024).is_err());
+
+        // a few bytes standing for more than the limit
+        let packed = Transport::Lzw.compress(&[0; 100_000]);
+        assert!(packed.len() < 1024);
+        assert!(matches!(Transport::Lzw.uncompress(&packed, 1024),
+                         Err(ZmodemError::ProtocolError(ProtocolError::SubpacketTooLong(1024)))));
+    }
+}
@@ -0,0 +1,389 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::error::{Result, ProtocolError};
+use crate::consts::*;
+use crate::crc::get_crc16;
+use crate::proto::read_byte;
+use crate::rwlog;
+use crate::send::{read_block, Options};
+
+/// Interval of repeating the receiver's initiating byte
+pub(crate) const INIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Error check requested by the receiver with its initiating byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// NAK: 8-bit arithmetic checksum
+    Checksum,
+
+    /// 'C': CRC-16
+    Crc,
+
+    /// 'G': CRC-16, blocks are streamed without ACK (YMODEM-G)
+    Streaming,
+}
+
+impl Check {
+    pub(crate) fn from_initiator(b: u8) -> Option<Check> {
+        match b {
+            NAK     => Some(Check::Checksum),
+            WANTCRC => Some(Check::Crc),
+            WANTG   => Some(Check::Streaming),
+            _       => None,
+        }
+    }
+
+    fn initiator(self) -> u8 {
+        match self {
+            Check::Checksum  => NAK,
+            Check::Crc       => WANTCRC,
+            Check::Streaming => WANTG,
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Check::Checksum => 1,
+            _               => 2,
+        }
+    }
+
+    fn compute(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Check::Checksum => vec![data.iter().fold(0, |sum: u8, x| sum.wrapping_add(*x))],
+            _               => get_crc16(data, None).to_vec(),
+        }
+    }
+}
+
+/// Sends data by XMODEM. Uses 1024 byte blocks (XMODEM-1K) if
+/// `options.block_size` allows, otherwise 128 byte blocks.
+/// The error check is chosen by the receiver.
+pub async fn send<RW, R>(rw: RW, r: &mut R, options: &Options) -> Result<RW>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin
+{
+    let mut rw_log = rwlog::ReadWriteLog::new(rw);
+
+    let check = wait_initiator(&mut rw_log).await?;
+    send_data(&mut rw_log, r, check, block_len(options)).await?;
+
+    Ok(rw_log.into_inner())
+}
+
+/// Receives data by XMODEM. Falls back from CRC to checksum mode if the
+/// sender doesn't respond. The last block is written with its padding.
+pub async fn recv<RW, W>(rw: RW, mut w: W, check: Check) -> Result<usize>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          W:  AsyncWrite + Unpin
+{
+    let mut rw_log = rwlog::ReadWriteLog::new(rw);
+
+    let (check, header) = start(&mut rw_log, check, true).await?;
+    let count = recv_data(&mut rw_log, &mut w, check, header, None).await?;
+    w.flush().await?;
+
+    Ok(count as usize)
+}
+
+pub(crate) fn block_len(options: &Options) -> usize {
+    if options.block_size >= 1024 { 1024 } else { 128 }
+}
+
+/// Waits for the receiver's initiating byte
+pub(crate) async fn wait_initiator<RW>(rw: &mut RW) -> Result<Check>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    let mut errors = 0;
+
+    loop {
+        let b = match timeout(TIMEOUT, read_byte(rw)).await {
+            Ok(b)  => b?,
+            Err(_) => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    return Err(ProtocolError::Timeout.into());
+                }
+                continue;
+            },
+        };
+
+        match (b, Check::from_initiator(b)) {
+            (_, Some(check)) => return Ok(check),
+            (CAN, _)         => check_cancel(rw).await?,
+            _                => debug!("ignoring {:02X} while waiting for receiver", b),
+        }
+    }
+}
+
+/// Waits for the receiver's initiating byte at most `duration`
+pub(crate) async fn poll_initiator<RW>(rw: &mut RW, duration: Duration) -> Result<Option<Check>>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    match timeout(duration, wait_initiator(rw)).await {
+        Ok(check) => check.map(Some),
+        Err(_)    => Ok(None),
+    }
+}
+
+/// Sends file content and EOT, returns number of bytes sent
+pub(crate) async fn send_data<RW, R>(rw: &mut RW, r: &mut R, check: Check, len: usize) -> Result<u64>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin {
+
+    let mut buf = vec![0; len];
+    let mut blk: u8 = 1;
+    let mut count = 0;
+
+    loop {
+        let num = read_block(r, &mut buf).await?;
+        if num == 0 {
+            break;
+        }
+
+        buf[num..].fill(CPMEOF);
+        let size = if num <= 128 { 128 } else { len };
+
+        send_block(rw, check, blk, &buf[..size]).await?;
+        blk = blk.wrapping_add(1);
+        count += num as u64;
+    }
+
+    send_eot(rw).await?;
+    Ok(count)
+}
+
+/// Sends one block and waits until it's acknowledged
+pub(crate) async fn send_block<RW>(rw: &mut RW, check: Check, blk: u8, data: &[u8]) -> Result<()>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    debug!("send block {} size={}", blk, data.len());
+
+    let mut packet = Vec::with_capacity(data.len() + 5);
+    packet.push(if data.len() == 128 { SOH } else { STX });
+    packet.extend_from_slice(&[blk, !blk]);
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(&check.compute(data));
+
+    for _ in 0..MAX_ERRORS {
+        rw.write_all(&packet).await?;
+        rw.flush().await?;
+
+        // block 0 is acknowledged even while streaming
+        if check == Check::Streaming && blk != 0 {
+            return Ok(());
+        }
+
+        match wait_response(rw).await? {
+            Some(ACK) => return Ok(()),
+            _         => debug!("block {} not acknowledged", blk),
+        }
+    }
+
+    write_cancel(rw).await?;
+    Err(ProtocolError::TooManyErrors.into())
+}
+
+async fn send_eot<RW>(rw: &mut RW) -> Result<()>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    for _ in 0..MAX_ERRORS {
+        debug!("send EOT");
+        rw.write_all(&[EOT]).await?;
+        rw.flush().await?;
+
+        if wait_response(rw).await? == Some(ACK) {
+            return Ok(());
+        }
+    }
+
+    Err(ProtocolError::TooManyErrors.into())
+}
+
+/// Reads ACK or NAK, None on timeout
+async fn wait_response<RW>(rw: &mut RW) -> Result<Option<u8>>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    loop {
+        let b = match timeout(TIMEOUT, read_byte(rw)).await {
+            Ok(b)  => b?,
+            Err(_) => return Ok(None),
+        };
+
+        match b {
+            ACK | NAK => return Ok(Some(b)),
+            CAN       => check_cancel(rw).await?,
+            _         => debug!("ignoring {:02X} while waiting for ACK", b),
+        }
+    }
+}
+
+/// Sends the initiating byte until the sender starts a block.
+/// With `fallback` CRC mode is downgraded to checksum after a few attempts.
+/// Returns the resulting check and the first header byte.
+pub(crate) async fn start<RW>(rw: &mut RW, mut check: Check, fallback: bool) -> Result<(Check, u8)>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    for i in 0..MAX_ERRORS {
+        if fallback && check == Check::Crc && i == MAX_ERRORS / 2 {
+            debug!("no response to CRC request, falling back to checksum");
+            check = Check::Checksum;
+        }
+
+        rw.write_all(&[check.initiator()]).await?;
+        rw.flush().await?;
+
+        if let Ok(b) = timeout(INIT_TIMEOUT, read_byte(rw)).await {
+            match b? {
+                CAN => check_cancel(rw).await?,
+                b   => return Ok((check, b)),
+            }
+        }
+    }
+
+    Err(ProtocolError::Timeout.into())
+}
+
+/// Receives blocks starting with `header` until EOT, writes at most
+/// `size` bytes if it's known. Returns number of bytes received.
+pub(crate) async fn recv_data<RW, W>(rw: &mut RW, w: &mut W, check: Check, header: u8, size: Option<u64>) -> Result<u64>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          W:  AsyncWrite + Unpin {
+
+    let mut header = Some(header);
+    let mut expected: u8 = 1;
+    let mut count = 0;
+    let mut errors = 0;
+
+    loop {
+        match header {
+            Some(EOT) => {
+                debug!("got EOT");
+                rw.write_all(&[ACK]).await?;
+                rw.flush().await?;
+                return Ok(count);
+            },
+            Some(h @ (SOH | STX)) => {
+                match recv_block(rw, check, h).await? {
+                    Some((blk, data)) if blk == expected => {
+                        let len = match size {
+                            Some(size) => (size - count).min(data.len() as u64) as usize,
+                            None       => data.len(),
+                        };
+                        w.write_all(&data[..len]).await?;
+                        count += len as u64;
+                        expected = expected.wrapping_add(1);
+                        errors = 0;
+
+                        if check != Check::Streaming {
+                            rw.write_all(&[ACK]).await?;
+                        }
+                    },
+                    Some((blk, _)) if blk == expected.wrapping_sub(1) => {
+                        debug!("duplicate block {}", blk);
+                        rw.write_all(&[ACK]).await?;
+                    },
+                    Some((blk, _)) => {
+                        error!("block out of sequence: got {}, expected {}", blk, expected);
+                        errors = recv_error(rw, check, errors).await?;
+                    },
+                    None => {
+                        errors = recv_error(rw, check, errors).await?;
+                    },
+                }
+                rw.flush().await?;
+            },
+            Some(CAN) => check_cancel(rw).await?,
+            Some(b)   => debug!("ignoring {:02X} while waiting for block", b),
+            None      => {
+                error!("timeout waiting for block");
+                errors = recv_error(rw, check, errors).await?;
+                rw.flush().await?;
+            },
+        }
+
+        header = timeout(TIMEOUT, read_byte(rw)).await.ok().transpose()?;
+    }
+}
+
+/// Reads the rest of a block after its header byte.
+/// Returns block number and data, None if the block is damaged.
+pub(crate) async fn recv_block<R>(r: &mut R, check: Check, header: u8) -> Result<Option<(u8, Vec<u8>)>>
+    where R: AsyncRead + Unpin {
+
+    let len = if header == SOH { 128 } else { 1024 };
+    let mut buf = vec![0; 2 + len + check.len()];
+
+    if timeout(TIMEOUT, r.read_exact(&mut buf)).await.is_err() {
+        error!("timeout reading block");
+        return Ok(None);
+    }
+
+    let (blk, nblk) = (buf[0], buf[1]);
+    let data = &buf[2..2 + len];
+
+    if blk != !nblk {
+        error!("bad block number: {:02X} {:02X}", blk, nblk);
+        return Ok(None);
+    }
+
+    if check.compute(data) != buf[2 + len..] {
+        error!("block {} check mismatch", blk);
+        return Ok(None);
+    }
+
+    debug!("got block {} size={}", blk, len);
+    Ok(Some((blk, data.to_vec())))
+}
+
+/// Discards input and asks for retransmission.
+/// Streaming mode can't retransmit, so the transfer is cancelled.
+async fn recv_error<RW>(rw: &mut RW, check: Check, errors: usize) -> Result<usize>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    if check == Check::Streaming || errors + 1 >= MAX_ERRORS {
+        write_cancel(rw).await?;
+        return Err(ProtocolError::TooManyErrors.into());
+    }
+
+    purge(rw).await?;
+    rw.write_all(&[NAK]).await?;
+    Ok(errors + 1)
+}
+
+/// Reads out input until the line is quiet
+async fn purge<R>(r: &mut R) -> Result<()>
+    where R: AsyncRead + Unpin {
+
+    while let Ok(b) = timeout(Duration::from_secs(1), read_byte(r)).await {
+        b?;
+    }
+    Ok(())
+}
+
+/// Returns an error if CAN is followed by another one
+pub(crate) async fn check_cancel<R>(r: &mut R) -> Result<()>
+    where R: AsyncRead + Unpin {
+
+    match timeout(Duration::from_secs(1), read_byte(r)).await {
+        Ok(Ok(CAN)) => Err(ProtocolError::Cancelled.into()),
+        Ok(Err(e))  => Err(e),
+        _           => Ok(()),
+    }
+}
+
+pub(crate) async fn write_cancel<W>(w: &mut W) -> Result<()>
+    where W: AsyncWrite + Unpin {
+
+    w.write_all(&[CAN; 8]).await?;
+    w.flush().await
+        .map_err(|e| e.into())
+}
+
+#[test]
+fn test_check() {
+    assert_eq!(Check::Checksum.compute(&[0x80, 0x80, 1]), [1]);
+    assert_eq!(Check::Crc.compute(b"123456789"), [0x31, 0xC3]); // CRC-16/XMODEM
+}
@@ -0,0 +1,198 @@
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::error::{Result, ProtocolError};
+use crate::consts::*;
+use crate::file_info::FileInfo;
+use crate::proto::read_byte;
+use crate::recv::Action;
+use crate::rwlog;
+use crate::send::{Options, Protocol, Statistics};
+use crate::xmodem::*;
+
+/// Sends a batch of files by YMODEM. Each file is announced in block 0
+/// with its name, size, modification time and mode; data goes in 1024
+/// byte blocks if `options.block_size` allows. YMODEM-G streaming is
+/// used when the receiver asks for it.
+pub async fn send_files<RW, R, I>(rw: RW, files: I, options: &Options) -> Result<RW>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin,
+          I:  IntoIterator<Item = (FileInfo, R)>
+{
+    let mut rw_log = rwlog::ReadWriteLog::new(rw);
+    send_batch(&mut rw_log, files, options, None, false).await?;
+    Ok(rw_log.into_inner())
+}
+
+/// Sends files by YMODEM, `check` is the receiver's initiating byte if it's
+/// already been read. With `detect_xmodem` a receiver asking for checksums or
+/// not asking for data after block 0 is taken for an XMODEM one and gets the
+/// first file only.
+pub(crate) async fn send_batch<RW, R, I>(rw: &mut RW, files: I, options: &Options, mut check: Option<Check>, detect_xmodem: bool) -> Result<Statistics>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin,
+          I:  IntoIterator<Item = (FileInfo, R)>
+{
+    let mut files = files.into_iter();
+    let mut stats = Statistics {
+        protocol: Protocol::Ymodem,
+        ..Default::default()
+    };
+
+    while let Some((info, mut r)) = files.next() {
+        let c = match check.take() {
+            Some(x) => x,
+            None    => wait_initiator(rw).await?,
+        };
+
+        // YMODEM requires CRC
+        if detect_xmodem && c == Check::Checksum {
+            return send_xmodem(rw, &mut r, c, 128, files.next().is_some()).await;
+        }
+
+        send_block(rw, c, 0, &header_block(&info)).await?;
+
+        let c = match detect_xmodem {
+            true  => match poll_initiator(rw, INIT_TIMEOUT).await? {
+                Some(x) => x,
+                None    => {
+                    // XMODEM receiver took block 0 for a duplicate
+                    return send_xmodem(rw, &mut r, c, block_len(options), files.next().is_some()).await;
+                },
+            },
+            false => wait_initiator(rw).await?,
+        };
+        stats.bytes += send_data(rw, &mut r, c, block_len(options)).await?;
+        stats.files += 1;
+        stats.max_block_size = block_len(options);
+        stats.min_block_size = block_len(options);
+    }
+
+    // empty block 0 ends the batch
+    let c = match check {
+        Some(x) => x,
+        None    => wait_initiator(rw).await?,
+    };
+    send_block(rw, c, 0, &[0; 128]).await?;
+
+    Ok(stats)
+}
+
+async fn send_xmodem<RW, R>(rw: &mut RW, r: &mut R, check: Check, len: usize, more: bool) -> Result<Statistics>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          R:  AsyncRead + Unpin {
+
+    debug!("receiver speaks XMODEM");
+    let bytes = send_data(rw, r, check, len).await?;
+
+    if more {
+        error!("XMODEM can't send more than one file, the rest of the batch is dropped");
+    }
+    Ok(Statistics {
+        protocol:       Protocol::Xmodem,
+        files:          1,
+        bytes,
+        min_block_size: len,
+        max_block_size: len,
+        ..Default::default()
+    })
+}
+
+/// Receives a batch of files by YMODEM, see `recv::recv_files`.
+/// YMODEM can't resume, so the offset of `Action::Accept` must be 0,
+/// skipped files are received and discarded.
+pub async fn recv_files<RW, W, F>(rw: RW, mut open: F, check: Check) -> Result<Vec<FileInfo>>
+    where RW: AsyncRead + AsyncWrite + Unpin,
+          W:  AsyncWrite + Unpin,
+          F:  FnMut(&FileInfo) -> Action<W>
+{
+    let mut rw_log = rwlog::ReadWriteLog::new(rw);
+    let mut received = Vec::new();
+
+    loop {
+        let info = recv_header_block(&mut rw_log, check).await?;
+
+        let info = match info {
+            Some(x) => x,
+            None    => break,
+        };
+        debug!("YMODEM file: {:?}", info);
+
+        let size = info.size.map(|x| x as u64);
+        let (check, header) = start(&mut rw_log, check, false).await?;
+
+        match open(&info) {
+            Action::Accept(mut w, _) => {
+                recv_data(&mut rw_log, &mut w, check, header, size).await?;
+                w.flush().await?;
+                received.push(info);
+            },
+            Action::Skip => {
+                debug!("Skipping {}", info.name);
+                recv_data(&mut rw_log, &mut tokio::io::sink(), check, header, size).await?;
+            },
+        }
+    }
+
+    Ok(received)
+}
+
+/// Builds block 0: "name\0length mtime mode serial\0" padded with zeros
+fn header_block(info: &FileInfo) -> Vec<u8> {
+    let info = FileInfo {
+        files_left: None,
+        bytes_left: None,
+        ..info.clone()
+    };
+
+    let (_, mut data) = info.to_zfile();
+    let len = if data.len() <= 128 { 128 } else { 1024 };
+    data.resize(len, 0);
+    data
+}
+
+/// Receives block 0, None if it ends the batch
+async fn recv_header_block<RW>(rw: &mut RW, check: Check) -> Result<Option<FileInfo>>
+    where RW: AsyncRead + AsyncWrite + Unpin {
+
+    let mut errors = 0;
+    let (_, mut header) = start(rw, check, false).await?;
+
+    loop {
+        let block = match header {
+            SOH | STX => recv_block(rw, check, header).await?,
+            _         => {
+                // skip garbage, e.g. a ZMODEM sender's ZRQINIT
+                if header == CAN {
+                    check_cancel(rw).await?;
+                }
+                debug!("ignoring {:02X} while waiting for block 0", header);
+                if let Ok(b) = timeout(TIMEOUT, read_byte(rw)).await {
+                    header = b?;
+                    continue;
+                }
+                None
+            },
+        };
+
+        match block {
+            Some((0, data)) => {
+                rw.write_all(&[ACK]).await?;
+                rw.flush().await?;
+
+                return match data[0] {
+                    0 => Ok(None),
+                    _ => Ok(Some(FileInfo::from_zfile([0; 4], &data))),
+                };
+            },
+            _ => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    write_cancel(rw).await?;
+                    return Err(ProtocolError::TooManyErrors.into());
+                }
+                header = start(rw, check, false).await?.1;
+            },
+        }
+    }
+}
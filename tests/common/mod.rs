@@ -0,0 +1,141 @@
+//! Helpers shared by the integration tests
+
+// every test binary uses a part of them
+#![allow(dead_code)]
+
+use lazy_static::lazy_static;
+use std::result;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+lazy_static! {
+    static ref LOG_INIT: result::Result<(), log::SetLoggerError> = env_logger::init();
+    /// Random file content, the tests send slices of it
+    pub static ref RND_VALUES: Vec<u8> = {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let mut buf = vec![0; 1024 * 1024 * 11];
+        rng.fill_bytes(&mut buf);
+        buf
+    };
+}
+
+/// Logs as configured by RUST_LOG, once per test binary
+pub fn init_log() {
+    let _ = LOG_INIT.is_ok();
+}
+
+/// A line between a sender and a receiver, the test moves the sender's data
+/// and may write to the sender in between. The receiver's replies pass
+/// through on their own.
+pub struct Line {
+    from_sender: ReadHalf<DuplexStream>,
+    to_receiver: WriteHalf<DuplexStream>,
+    to_sender:   Arc<Mutex<WriteHalf<DuplexStream>>>,
+    replies:     JoinHandle<()>,
+    /// Everything the sender wrote so far
+    pub wire:    Vec<u8>,
+    /// Length of the wire passed to the receiver
+    passed:      usize,
+}
+
+impl Line {
+    /// Returns the line with the ends of the sender and the receiver
+    pub fn new() -> (Line, DuplexStream, DuplexStream) {
+        let (a, line_a) = duplex(1024);
+        let (b, line_b) = duplex(1024);
+        let (from_sender, to_sender) = split(line_a);
+        let (mut from_receiver, to_receiver) = split(line_b);
+        let to_sender = Arc::new(Mutex::new(to_sender));
+
+        let replies = tokio::spawn({
+            let to_sender = to_sender.clone();
+            async move {
+                let mut buf = [0; 1024];
+                while let Ok(n @ 1..) = from_receiver.read(&mut buf).await {
+                    if to_sender.lock().await.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        (Line { from_sender, to_receiver, to_sender, replies, wire: Vec::new(), passed: 0 }, a, b)
+    }
+
+    /// Forwards the sender's data until the sender is done, quiet for
+    /// `wait` or `limit` bytes passed. Returns the number of bytes.
+    pub async fn forward(&mut self, wait: Duration, limit: usize) -> usize {
+        let mut total = self.pass(self.wire.len()).await;
+        while total < limit && self.read(wait).await {
+            total += self.pass(self.wire.len()).await;
+        }
+        total
+    }
+
+    /// Forwards the sender's data up to `pattern`, the rest of it passes
+    /// with the next forward
+    pub async fn forward_until(&mut self, pattern: &[u8]) {
+        loop {
+            let start = self.passed;
+            if let Some(i) = self.wire[start..].windows(pattern.len()).position(|x| x == pattern) {
+                self.pass(start + i).await;
+                return;
+            }
+            // keeps the start of a match
+            let end = self.wire.len();
+            let keep = (1..pattern.len()).rev()
+                .find(|&n| end - start >= n && self.wire[end - n..] == pattern[..n])
+                .unwrap_or(0);
+            self.pass(end - keep).await;
+            assert!(self.read(Duration::from_secs(10)).await, "no {:?} from the sender", pattern);
+        }
+    }
+
+    /// Forwards the sender's data until it's done
+    pub async fn forward_all(&mut self) -> usize {
+        self.forward(Duration::from_secs(10), usize::MAX).await
+    }
+
+    /// Writes to the sender between the receiver's replies
+    pub async fn write_to_sender(&self, data: &[u8]) {
+        self.to_sender.lock().await.write_all(data).await.unwrap();
+    }
+
+    /// Writes to the receiver between the sender's data
+    pub async fn write_to_receiver(&mut self, data: &[u8]) {
+        self.to_receiver.write_all(data).await.unwrap();
+    }
+
+    /// Reads the sender's data onto the wire, false once the sender is
+    /// done or quiet for `wait`
+    async fn read(&mut self, wait: Duration) -> bool {
+        let mut buf = [0; 1024];
+        match timeout(wait, self.from_sender.read(&mut buf)).await {
+            Ok(Ok(n @ 1..)) => {
+                self.wire.extend_from_slice(&buf[..n]);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Passes the wire up to `end` to the receiver
+    async fn pass(&mut self, end: usize) -> usize {
+        // the receiver may be done before the sender
+        let _ = self.to_receiver.write_all(&self.wire[self.passed..end]).await;
+        let n = end - self.passed;
+        self.passed = end;
+        n
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        self.replies.abort();
+    }
+}
@@ -0,0 +1,177 @@
+//! Transfers over a link that damages the sender's data
+
+mod common;
+
+use common::{init_log, RND_VALUES};
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io;
+use zmodem::{AsyncReadWrite, Escape, FileInfo, ProtocolError, ZmodemError};
+use zmodem::recv::{self, Action};
+use zmodem::send::{self, Options, Statistics};
+use zmodem::testing::{link, Impairments};
+
+/// Sends `len` bytes over a link flipping bits of the sender's data with
+/// probability `bit_flips`, checks them at the receiver
+async fn transfer(len: usize, bit_flips: f64, options: Options) -> (Statistics, recv::Statistics) {
+    init_log();
+
+    let damaged = Impairments { bit_flips, ..Default::default() };
+    let (a, b) = link(damaged, Default::default(), 1);
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..len]))];
+        zmodem::send::send_files(a, files, &options).await.unwrap().1
+    });
+
+    let (br, mut bw) = io::split(b);
+    let mut received = Vec::new();
+    let mut output = Some(&mut received);
+    // don't wait long when a damaged header stalls both sides
+    let recv_options = recv::Options { timeout: Duration::from_secs(1), ..Default::default() };
+    let (_, stats) = recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), 0), &recv_options)
+        .await.unwrap();
+
+    assert_eq!(received.len(), len);
+    assert!(received[..] == RND_VALUES[..len]);
+    assert_eq!(stats.bytes, len as u64);
+    (sender.await.unwrap(), stats)
+}
+
+#[tokio::test]
+async fn clean_link() {
+    let (stats, recv_stats) = transfer(300_000, 0.0, Options::default()).await;
+    assert_eq!(stats.rewinds, 0);
+    assert_eq!(stats.bytes, 300_000);
+    assert_eq!(recv_stats, recv::Statistics { files: 1, bytes: 300_000, ..Default::default() });
+}
+
+#[tokio::test]
+async fn rewind_mid_burst() {
+    let (stats, recv_stats) = transfer(300_000, 2e-5, Options::default()).await;
+    assert!(stats.rewinds >= 1);
+    assert!(stats.bytes > 300_000);
+    assert!(recv_stats.crc_errors + recv_stats.bad_headers >= 1);
+    assert!(recv_stats.rewinds >= 1);
+}
+
+#[tokio::test]
+async fn rewind_repeatedly() {
+    let (stats, _) = transfer(300_000, 3e-5, Options::default()).await;
+    assert!(stats.rewinds >= 1);
+}
+
+#[tokio::test]
+async fn rewind_crc16_escaped() {
+    let options = Options {
+        block_size: 1024,
+        crc16:      true,
+        escape:     Escape::CONTROLS,
+        ..Default::default()
+    };
+    let (stats, _) = transfer(100_000, 5e-5, options).await;
+    assert!(stats.rewinds >= 1);
+}
+
+#[tokio::test]
+async fn rewind_small_window() {
+    let options = Options {
+        block_size: 1024,
+        window:     4096,
+        ..Default::default()
+    };
+    let (stats, _) = transfer(100_000, 5e-5, options).await;
+    assert!(stats.rewinds >= 1);
+}
+
+#[tokio::test]
+async fn adaptive_block_size() {
+    let options = Options {
+        adaptive: true,
+        ..Default::default()
+    };
+    let (stats, _) = transfer(500_000, 1e-5, options).await;
+    assert!(stats.rewinds >= 1);
+    assert!(stats.min_block_size < 8192);
+    assert_eq!(stats.max_block_size, 8192);
+}
+
+#[tokio::test]
+async fn subpackets_too_long() {
+    init_log();
+
+    // the limit isn't advertised, every subpacket is damaged for the receiver
+    let (a, b) = io::duplex(16 * 1024);
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..100_000]))];
+        send::send_files(a, files, &Options::default()).await
+    });
+
+    let options = recv::Options { max_subpacket: 1024, ..Default::default() };
+    let result = recv::recv_files(b, |_| Action::Accept(io::sink(), 0), &options).await;
+    assert!(matches!(result, Err(ZmodemError::ProtocolError(ProtocolError::TooManyErrors))));
+    assert!(sender.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn silent_receiver() {
+    init_log();
+
+    let (a, _b) = io::duplex(16 * 1024);
+    let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..1000]))];
+    let options = Options { timeout: Duration::from_millis(10), ..Default::default() };
+    let result = send::send_files(a, files, &options).await;
+    assert!(matches!(result, Err(ZmodemError::ProtocolError(ProtocolError::Timeout))));
+}
+
+#[tokio::test]
+async fn damaged_replies() {
+    use tokio::io::AsyncWriteExt;
+
+    init_log();
+
+    // ZRINIT with a bad CRC, over and over
+    let (a, mut b) = io::duplex(16 * 1024);
+    b.write_all(&b"**\x18B0100000000dead\r\n\x11".repeat(20)).await.unwrap();
+
+    let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..1000]))];
+    let result = send::send_files(a, files, &Options::default()).await;
+    assert!(matches!(result, Err(ZmodemError::ProtocolError(ProtocolError::TooManyErrors))));
+}
+
+#[tokio::test]
+async fn lost_zeof() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init_log();
+
+    // a receiver taking the file that never answers ZEOF
+    let (a, mut b) = io::duplex(16 * 1024);
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), &RND_VALUES[..1000])];
+        let options = Options { timeout: Duration::from_millis(10), ..Default::default() };
+        send::send_stream(a, files, &options).await
+    });
+
+    // ZRINIT, then ZRPOS 0 after ZFILE
+    b.write_all(b"**\x18B0100000023be50\r\n\x11").await.unwrap();
+    let mut wire = Vec::new();
+    let mut buf = [0; 1024];
+    let mut zrpos = false;
+    let zeofs = |wire: &[u8]| wire.windows(3).filter(|x| x == b"\x18C\x0b").count();
+
+    while zeofs(&wire) < 3 {
+        match b.read(&mut buf).await.unwrap() {
+            0 => break,
+            n => wire.extend_from_slice(&buf[..n]),
+        }
+        if !zrpos && wire.windows(3).any(|x| x == b"\x18C\x04") {
+            zrpos = true;
+            b.write_all(b"**\x18B0900000000a87c\r\n\x11").await.unwrap();
+        }
+    }
+
+    assert_eq!(zeofs(&wire), 3);
+    drop(b);
+    assert!(sender.await.unwrap().is_err());
+}
@@ -1,73 +1,22 @@
-use lazy_static::lazy_static;
-use pin_project_lite::pin_project;
+mod common;
+
+use common::{init_log, Line, RND_VALUES};
 use std::io::Cursor;
-use std::pin::Pin;
 use std::process::Stdio;
-use std::result;
-use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::fs::{File, OpenOptions, remove_file};
-use tokio::io;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
 use tokio::time::sleep;
-
-pin_project! {
-    pub struct AsyncReadWrite<R, W> {
-        #[pin]
-        inner_read:  R,
-        #[pin]
-        inner_write: W,
-    }
-}
-
-impl<R, W> AsyncReadWrite<R, W>
-        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
-    pub fn new(read: R, write: W) -> Self {
-        Self {
-            inner_read:  read,
-            inner_write: write,
-        }
-    }
-}
-
-impl<R, W> AsyncRead for AsyncReadWrite<R, W>
-        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
-    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut io::ReadBuf<'_>) -> Poll<io::Result<()>> {
-        self.project().inner_read.poll_read(cx, buf)
-    }
-}
-
-impl<R, W> AsyncWrite for AsyncReadWrite<R, W>
-        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
-    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        self.project().inner_write.poll_write(cx, buf)
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project().inner_write.poll_flush(cx)
-    }
-
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.project().inner_write.poll_shutdown(cx)
-    }
-}
-
-lazy_static! {
-    static ref LOG_INIT: result::Result<(), log::SetLoggerError> = env_logger::init();
-    static ref RND_VALUES: Vec<u8> = {
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
-        let mut buf = vec![0; 1024 * 1024 * 11];
-        rng.fill_bytes(&mut buf);
-        buf
-    };
-}
+use zmodem::{AsyncReadWrite, Escape, FileInfo, ProtocolError, Transport, ZmodemError};
+use zmodem::recv::Action;
+use zmodem::send::{Options, Protocol};
+use zmodem::xmodem::Check;
 
 #[tokio::test]
 #[cfg(unix)]
 async fn recv_from_sz() {
-    let _ = LOG_INIT.is_ok();
+    init_log();
 
     let mut f = File::create("recv_from_sz").await.unwrap();
     f.write_all(&RND_VALUES).await.unwrap();
@@ -95,9 +44,9 @@ async fn recv_from_sz() {
 #[tokio::test]
 #[cfg(unix)]
 async fn send_to_rz() {
-    let _ = LOG_INIT.is_ok();
+    init_log();
 
-    let _ = remove_file("send_to_rz");
+    let _ = remove_file("send_to_rz").await;
 
     let sz = Command::new("rz")
             .stdout(Stdio::piped())
@@ -130,22 +79,24 @@ async fn send_to_rz() {
 #[tokio::test]
 #[cfg(unix)]
 async fn lib_send_recv() {
-    let _ = LOG_INIT;
+    init_log();
 
-    let _ = remove_file("test-fifo1");
-    let _ = remove_file("test-fifo2");
+    let _ = remove_file("test-fifo1").await;
+    let _ = remove_file("test-fifo2").await;
 
     let _ = Command::new("mkfifo")
             .arg("test-fifo1")
             .spawn()
             .expect("mkfifo failed to run")
-            .wait();
+            .wait()
+            .await;
 
     let _ = Command::new("mkfifo")
             .arg("test-fifo2")
             .spawn()
             .expect("mkfifo failed to run")
-            .wait();
+            .wait()
+            .await;
 
     sleep(Duration::from_millis(300)).await;
 
@@ -173,3 +124,546 @@ async fn lib_send_recv() {
 
     assert_eq!(RND_VALUES.clone(), c.into_inner());
 }
+
+#[tokio::test]
+async fn lib_send_recv_batch() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let names = ["first", "skipped", "third"];
+
+    tokio::spawn(async move {
+        let files = names.into_iter().enumerate().map(|(i, name)| {
+            let info = FileInfo {
+                size: Some((i as u32 + 1) * 10000),
+                ..FileInfo::new(name)
+            };
+            (info, Cursor::new(&RND_VALUES[..(i + 1) * 10000]))
+        }).collect::<Vec<_>>();
+        let options = Options {
+            block_size: 1024,
+            crc16:      true,
+            escape:     Escape::CONTROLS,
+            ..Default::default()
+        };
+
+        zmodem::send::send_files(a, files, &options).await.unwrap();
+    });
+
+    let mut received = vec![Vec::new(); 2];
+    let mut outputs = received.iter_mut();
+    let files = zmodem::recv::recv_files(b, |info| {
+        match info.name.as_str() {
+            "skipped" => Action::Skip,
+            _         => Action::Accept(outputs.next().unwrap(), 0),
+        }
+    }, &Default::default()).await.unwrap().0;
+
+    assert_eq!(files.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["first", "third"]);
+    assert_eq!(files[1].size, Some(30000));
+    assert_eq!(received[0][..], RND_VALUES[..10000]);
+    assert_eq!(received[1][..], RND_VALUES[..30000]);
+}
+
+#[tokio::test]
+async fn xmodem_send_recv() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(4096);
+    let len = 128 * 20 + 5;
+
+    tokio::spawn(async move {
+        let mut c = Cursor::new(&RND_VALUES[..len]);
+        let options = Options {
+            block_size: 128,
+            ..Default::default()
+        };
+
+        zmodem::xmodem::send(a, &mut c, &options).await.unwrap();
+    });
+
+    let mut received = Vec::new();
+    let count = zmodem::xmodem::recv(b, &mut received, Check::Checksum).await.unwrap();
+
+    // the last block is padded up to 128 bytes
+    assert_eq!(count, 128 * 21);
+    assert_eq!(received[..len], RND_VALUES[..len]);
+    assert!(received[len..].iter().all(|&x| x == 0x1A));
+}
+
+#[tokio::test]
+async fn ymodem_send_recv_batch() {
+    init_log();
+
+    for check in [Check::Crc, Check::Streaming] {
+        let (a, b) = tokio::io::duplex(4096);
+        let sizes = [3000, 100, 1024];
+
+        tokio::spawn(async move {
+            let files = sizes.iter().enumerate().map(|(i, &size)| {
+                let info = FileInfo {
+                    size:  Some(size as u32),
+                    mtime: Some(1_000_000_000),
+                    ..FileInfo::new(&format!("file{}", i))
+                };
+                (info, Cursor::new(&RND_VALUES[..size]))
+            }).collect::<Vec<_>>();
+
+            zmodem::ymodem::send_files(a, files, &Options::default()).await.unwrap();
+        });
+
+        let mut received = vec![Vec::new(); 3];
+        let mut outputs = received.iter_mut();
+        let files = zmodem::ymodem::recv_files(b, |_| Action::Accept(outputs.next().unwrap(), 0), check)
+            .await.unwrap();
+
+        assert_eq!(files.len(), 3);
+        for (i, size) in sizes.iter().enumerate() {
+            assert_eq!(files[i].name, format!("file{}", i));
+            assert_eq!(files[i].mtime, Some(1_000_000_000));
+            assert_eq!(received[i][..], RND_VALUES[..*size]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn send_auto_fallback() {
+    init_log();
+
+    let len = 5000;
+    let files = move || vec![(FileInfo { size: Some(len as u32), ..FileInfo::new("file") }, Cursor::new(&RND_VALUES[..len]))];
+
+    // ZMODEM
+    let (a, b) = tokio::io::duplex(4096);
+    let sender = tokio::spawn(async move {
+        zmodem::send::send_auto(a, files(), &Options::default()).await.unwrap().1.protocol
+    });
+    // keep the write half open for the sender's final "OO"
+    let (br, mut bw) = tokio::io::split(b);
+    let mut received = Vec::new();
+    zmodem::recv::recv(AsyncReadWrite::new(br, &mut bw), &mut received).await.unwrap();
+    assert_eq!(sender.await.unwrap(), Protocol::Zmodem);
+    assert_eq!(received[..], RND_VALUES[..len]);
+
+    // YMODEM
+    let (a, b) = tokio::io::duplex(4096);
+    let sender = tokio::spawn(async move {
+        zmodem::send::send_auto(a, files(), &Options::default()).await.unwrap().1.protocol
+    });
+    let mut received = [Vec::new()];
+    let mut outputs = received.iter_mut();
+    zmodem::ymodem::recv_files(b, |_| Action::Accept(outputs.next().unwrap(), 0), Check::Crc).await.unwrap();
+    assert_eq!(sender.await.unwrap(), Protocol::Ymodem);
+    assert_eq!(received[0][..], RND_VALUES[..len]);
+
+    // XMODEM with CRC and with checksum
+    for check in [Check::Crc, Check::Checksum] {
+        let (a, b) = tokio::io::duplex(4096);
+        let sender = tokio::spawn(async move {
+            zmodem::send::send_auto(a, files(), &Options::default()).await.unwrap().1.protocol
+        });
+        let mut received = Vec::new();
+        zmodem::xmodem::recv(b, &mut received, check).await.unwrap();
+        assert_eq!(sender.await.unwrap(), Protocol::Xmodem);
+        assert_eq!(received[..len], RND_VALUES[..len]);
+    }
+}
+
+#[tokio::test]
+async fn send_stream_unknown_size() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(4096);
+    let len = 100_000;
+
+    tokio::spawn(async move {
+        // a slice reads but doesn't seek
+        let data: &'static [u8] = &RND_VALUES[..len];
+        let files = [(FileInfo::new("stream"), data)];
+        zmodem::send::send_stream(a, files, &Options::default()).await.unwrap();
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let mut received = [Vec::new()];
+    let mut outputs = received.iter_mut();
+    let (files, _) = zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(outputs.next().unwrap(), 0), &Default::default())
+        .await.unwrap();
+
+    assert_eq!(files[0].size, None);
+    assert_eq!(received[0][..], RND_VALUES[..len]);
+}
+
+#[tokio::test]
+async fn send_stream_resume_beyond_end() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(4096);
+
+    let sender = tokio::spawn(async move {
+        let data: &'static [u8] = &RND_VALUES[..1000];
+        let files = [(FileInfo::new("stream"), data)];
+        zmodem::send::send_stream(a, files, &Options::default()).await.map(|_| ())
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let result = zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(Vec::new(), 5000), &Default::default())
+        .await;
+
+    assert!(matches!(result, Err(ZmodemError::ProtocolError(ProtocolError::RemoteAborted))));
+    assert!(matches!(sender.await.unwrap(),
+                     Err(ZmodemError::ProtocolError(ProtocolError::OffsetMismatch { expected: 5000, actual: 1000 }))));
+}
+
+#[tokio::test]
+async fn recv_stream_batch() {
+    use futures_util::StreamExt;
+
+    init_log();
+
+    let (a, b) = tokio::io::duplex(4096);
+    let sizes = [50_000, 0, 200_000];
+
+    tokio::spawn(async move {
+        let files = sizes.iter().enumerate().map(|(i, &size)| {
+            let info = FileInfo {
+                size: Some(size as u32),
+                ..FileInfo::new(&format!("file{}", i))
+            };
+            (info, Cursor::new(&RND_VALUES[..size]))
+        }).collect::<Vec<_>>();
+
+        zmodem::send::send_files(a, files, &Options::default()).await.unwrap();
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let (session, mut files) = zmodem::recv::recv_stream(AsyncReadWrite::new(br, &mut bw), &Default::default());
+
+    let consumer = async {
+        let mut received = Vec::new();
+        while let Some((info, mut data)) = files.next().await {
+            let mut content = Vec::new();
+            while let Some(chunk) = data.next().await {
+                content.extend_from_slice(&chunk.unwrap());
+                // a slow consumer holds off the sender
+                sleep(Duration::from_millis(1)).await;
+            }
+            received.push((info.name, content));
+        }
+        received
+    };
+
+    let (result, received) = tokio::join!(session, consumer);
+    assert_eq!(result.unwrap().0.len(), 3);
+    assert_eq!(received.len(), 3);
+    for (i, size) in sizes.iter().enumerate() {
+        assert_eq!(received[i].0, format!("file{}", i));
+        assert_eq!(received[i].1[..], RND_VALUES[..*size]);
+    }
+}
+
+#[tokio::test]
+async fn lib_send_recv_small_window() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(1024);
+    let len = 100_003;
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..len]))];
+        let options = Options {
+            block_size: 1024,
+            window:     3000,
+            ..Default::default()
+        };
+
+        zmodem::send::send_files(a, files, &options).await.unwrap().1
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let mut received = Vec::new();
+    zmodem::recv::recv(AsyncReadWrite::new(br, &mut bw), &mut received).await.unwrap();
+
+    assert_eq!(received[..], RND_VALUES[..len]);
+
+    let stats = sender.await.unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.bytes, len as u64);
+    assert_eq!(stats.rewinds, 0);
+    assert_eq!((stats.min_block_size, stats.max_block_size), (1024, 1024));
+}
+
+#[tokio::test]
+async fn lib_send_recv_zero_sizes() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(1024);
+    let len = 1000;
+
+    let sender = tokio::spawn(async move {
+        let data: &'static [u8] = &RND_VALUES[..len];
+        let files = [(FileInfo::new("stream"), data)];
+        let options = Options {
+            block_size: 0,
+            window:     0,
+            ..Default::default()
+        };
+
+        zmodem::send::send_stream(a, files, &options).await.unwrap().1
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let mut received = Vec::new();
+    zmodem::recv::recv(AsyncReadWrite::new(br, &mut bw), &mut received).await.unwrap();
+
+    assert_eq!(received[..], RND_VALUES[..len]);
+    assert_eq!(sender.await.unwrap().max_block_size, 1);
+}
+
+#[tokio::test]
+async fn lib_send_recv_buffer_size() {
+    init_log();
+
+    let (a, b) = tokio::io::duplex(4096);
+    let len = 50_000;
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..len]))];
+        zmodem::send::send_files(a, files, &Options::default()).await.unwrap().1
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let mut received = [Vec::new()];
+    let mut outputs = received.iter_mut();
+    let options = zmodem::recv::Options {
+        buffer_size:   2048,
+        ..Default::default()
+    };
+    let (_, recv_stats) = zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(outputs.next().unwrap(), 0), &options)
+        .await.unwrap();
+
+    assert_eq!(received[0][..], RND_VALUES[..len]);
+    assert_eq!(recv_stats.crc_errors, 0);
+
+    // subpackets fit into the advertised buffer
+    let stats = sender.await.unwrap();
+    assert_eq!(stats.max_block_size, 2048);
+}
+
+#[tokio::test]
+async fn lib_send_recv_rle() {
+    init_log();
+
+    // an image with padding, resumed in the middle of it
+    let data = [&RND_VALUES[..10_000], &[0xff; 50_000], &RND_VALUES[..10_000]].concat();
+    let offset = 30_000;
+
+    // plain data to receivers that don't advertise it
+    for transports in [vec![Transport::Rle], vec![]] {
+        let (a, b) = tokio::io::duplex(4096);
+
+        let sender = tokio::spawn({
+            let data = data.clone();
+            async move {
+                let mut trace = Vec::new();
+                let files = vec![(FileInfo::new("image"), Cursor::new(data))];
+                let options = Options { transport: Transport::Rle, ..Default::default() };
+                let a = zmodem::capture::Capture::new(a, &mut trace, zmodem::capture::Format::Binary);
+                let (_, stats) = zmodem::send::send_files(a, files, &options).await.unwrap();
+                (stats, trace.len())
+            }
+        });
+
+        let (br, mut bw) = tokio::io::split(b);
+        let mut received = Vec::new();
+        let mut output = Some(&mut received);
+        let options = zmodem::recv::Options { transports: transports.clone(), ..Default::default() };
+        let (_, recv_stats) = zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), offset), &options)
+            .await.unwrap();
+
+        assert_eq!(received[..], data[offset as usize..]);
+        assert_eq!(recv_stats.bytes, data.len() as u64);
+
+        let (stats, sent) = sender.await.unwrap();
+        assert_eq!(stats.bytes, (data.len() - offset as usize) as u64);
+        assert_eq!(stats.rewinds, 0);
+        assert_eq!(sent < 30_000, !transports.is_empty(), "{} bytes sent", sent);
+    }
+}
+
+#[tokio::test]
+async fn lib_send_recv_lzw() {
+    init_log();
+
+    let log = (0..5000).map(|i| format!("{:06} INFO link {} ok\n", i, i % 7)).collect::<String>().into_bytes();
+
+    // CANLZW is for ZTRLE and ZTLZW, not our LZW
+    for transports in [vec![Transport::Lzw], vec![Transport::Rle]] {
+        let (a, b) = tokio::io::duplex(4096);
+
+        let sender = tokio::spawn({
+            let log = log.clone();
+            async move {
+                let mut trace = Vec::new();
+                let files = vec![(FileInfo::new("log"), Cursor::new(log))];
+                let options = Options { transport: Transport::Lzw, ..Default::default() };
+                let a = zmodem::capture::Capture::new(a, &mut trace, zmodem::capture::Format::Binary);
+                let (_, stats) = zmodem::send::send_files(a, files, &options).await.unwrap();
+                (stats, trace.len())
+            }
+        });
+
+        let (br, mut bw) = tokio::io::split(b);
+        let mut received = Vec::new();
+        let mut output = Some(&mut received);
+        let options = zmodem::recv::Options { transports: transports.clone(), ..Default::default() };
+        zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), 0), &options)
+            .await.unwrap();
+
+        assert!(received == log);
+        let (stats, sent) = sender.await.unwrap();
+        assert_eq!(stats.bytes, log.len() as u64);
+        assert_eq!(sent < log.len() / 2, transports == [Transport::Lzw], "{} bytes sent", sent);
+    }
+}
+
+#[tokio::test]
+async fn lib_send_xon_xoff() {
+    init_log();
+
+    let len = 100_000;
+    let (mut line, a, b) = Line::new();
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..len]))];
+        let options = Options { xon_xoff: true, ..Default::default() };
+        zmodem::send::send_files(a, files, &options).await.unwrap();
+    });
+    let receiver = tokio::spawn(async move {
+        let (br, mut bw) = tokio::io::split(b);
+        let mut received = Vec::new();
+        zmodem::recv::recv(AsyncReadWrite::new(br, &mut bw), &mut received).await.unwrap();
+        received
+    });
+
+    // the line stops the sender for a while
+    assert!(line.forward(Duration::from_secs(1), 20_000).await >= 20_000);
+    line.write_to_sender(&[0x13]).await;
+    // the subpacket on the way
+    line.forward(Duration::from_millis(100), usize::MAX).await;
+    assert_eq!(line.forward(Duration::from_millis(500), usize::MAX).await, 0);
+
+    line.write_to_sender(&[0x11]).await;
+    assert!(line.forward(Duration::from_secs(1), usize::MAX).await > 0);
+
+    sender.await.unwrap();
+    assert_eq!(receiver.await.unwrap()[..], RND_VALUES[..len]);
+}
+
+#[tokio::test]
+async fn send_stream_stray_frame() {
+    init_log();
+
+    let len = 300_000;
+    let (mut line, a, b) = Line::new();
+
+    let sender = tokio::spawn(async move {
+        let data: &'static [u8] = &RND_VALUES[..len];
+        let files = [(FileInfo::new("stream"), data)];
+        zmodem::send::send_stream(a, files, &Options::default()).await.unwrap().1
+    });
+    let receiver = tokio::spawn(async move {
+        let (br, mut bw) = tokio::io::split(b);
+        let mut received = Vec::new();
+        zmodem::recv::recv(AsyncReadWrite::new(br, &mut bw), &mut received).await.unwrap();
+        received
+    });
+
+    // ZNAK at offset 0 while data is flowing, beyond what can be resent
+    line.forward(Duration::from_secs(10), 200_000).await;
+    line.write_to_sender(b"**\x18B0600000000cd85\r\n\x11").await;
+    line.forward_all().await;
+
+    let stats = sender.await.unwrap();
+    assert_eq!(receiver.await.unwrap()[..], RND_VALUES[..len]);
+    assert_eq!(stats.rewinds, 0);
+}
+
+#[tokio::test]
+async fn recv_stray_frame() {
+    init_log();
+
+    let len = 300_000;
+    let (mut line, a, b) = Line::new();
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..len]))];
+        zmodem::send::send_files(a, files, &Options::default()).await.unwrap();
+    });
+    let receiver = tokio::spawn(async move {
+        let (br, mut bw) = tokio::io::split(b);
+        let mut received = Vec::new();
+        let mut output = Some(&mut received);
+        let (_, stats) = zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), 0), &Default::default())
+            .await.unwrap();
+        (received, stats)
+    });
+
+    // ZNAK at offset 0 before ZEOF, when the receiver expects a header
+    line.forward_until(b"*\x18C\x0b").await;
+    line.write_to_receiver(b"**\x18B0600000000cd85\r\n\x11").await;
+    line.forward_all().await;
+
+    sender.await.unwrap();
+    let (received, stats) = receiver.await.unwrap();
+    assert_eq!(received[..], RND_VALUES[..len]);
+    assert_eq!(stats.rewinds, 0);
+}
+
+/// Sends a file through a line recording what the sender writes
+async fn transfer_escaped(send_escape: Escape, recv_escape: Escape) -> Vec<u8> {
+    let len = 100_000;
+    let (mut line, a, b) = Line::new();
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..len]))];
+        let options = Options { escape: send_escape, ..Default::default() };
+        zmodem::send::send_files(a, files, &options).await.unwrap();
+    });
+    let receiver = tokio::spawn(async move {
+        let (br, mut bw) = tokio::io::split(b);
+        let mut received = Vec::new();
+        let mut output = Some(&mut received);
+        let options = zmodem::recv::Options { escape: recv_escape, ..Default::default() };
+        zmodem::recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), 0), &options).await.unwrap();
+        received
+    });
+
+    line.forward_all().await;
+
+    sender.await.unwrap();
+    assert_eq!(receiver.await.unwrap()[..], RND_VALUES[..len]);
+    std::mem::take(&mut line.wire)
+}
+
+#[tokio::test]
+async fn lib_send_recv_escaped() {
+    init_log();
+
+    // asked by the sender, passed on in ZSINIT
+    let escape = Escape { controls: true, telnet: true, eight_bit: true };
+    let wire = transfer_escaped(escape, Escape::SEVEN_BIT).await;
+    assert!(wire.iter().all(|x| x & 0x80 == 0));
+    assert!(!wire.windows(2).any(|x| x == b"@\r"));
+
+    // a receiver without XESC8 doesn't decode ESC_8
+    let wire = transfer_escaped(escape, Escape::MINIMAL).await;
+    assert!(wire.iter().any(|x| x & 0x80 != 0));
+    assert!(!wire.windows(2).any(|x| x == b"@\r"));
+
+    // asked by the receiver in ZRINIT
+    let wire = transfer_escaped(Escape::MINIMAL, Escape::SEVEN_BIT).await;
+    assert!(wire.iter().all(|x| x & 0x80 == 0));
+
+    let wire = transfer_escaped(Escape::MINIMAL, Escape::MINIMAL).await;
+    assert!(wire.iter().any(|x| x & 0x80 != 0));
+}
@@ -0,0 +1,135 @@
+//! Metrics recorded by sessions with the `metrics` feature
+
+use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io;
+use zmodem::{AsyncReadWrite, FileInfo};
+use zmodem::recv::{self, Action};
+use zmodem::send;
+use zmodem::testing::{link, Impairments};
+
+const LEN: usize = 100_000;
+
+/// Label names and values
+type Labels = Vec<(String, String)>;
+
+/// Metrics found in a snapshot, as name, labels and value
+struct Metrics(Vec<(String, Labels, DebugValue)>);
+
+impl Metrics {
+    fn new(snapshotter: &Snapshotter) -> Metrics {
+        let metrics = snapshotter.snapshot().into_vec().into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels = key.labels().map(|x| (x.key().to_string(), x.value().to_string())).collect();
+                (key.name().to_string(), labels, value)
+            })
+            .collect();
+        Metrics(metrics)
+    }
+
+    /// Sum of the counters matching the name and labels
+    fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        self.matching(name, labels)
+            .map(|x| match x {
+                DebugValue::Counter(n) => *n,
+                _                      => panic!("{} isn't a counter", name),
+            })
+            .sum()
+    }
+
+    /// Values of the histograms matching the name and labels
+    fn histogram(&self, name: &str, labels: &[(&str, &str)]) -> Vec<f64> {
+        self.matching(name, labels)
+            .flat_map(|x| match x {
+                DebugValue::Histogram(v) => v.iter().map(|x| x.into_inner()).collect::<Vec<_>>(),
+                _                        => panic!("{} isn't a histogram", name),
+            })
+            .collect()
+    }
+
+    fn matching<'a>(&'a self, name: &'a str, labels: &'a [(&'a str, &'a str)]) -> impl Iterator<Item = &'a DebugValue> {
+        self.0.iter()
+            .filter(move |(x, l, _)| x == name && labels.iter().all(|(k, v)| l.iter().any(|(a, b)| a == k && b == v)))
+            .map(|(_, _, value)| value)
+    }
+}
+
+/// Runs a session on a runtime of this thread, so it records to `recorder`
+fn run<F: std::future::Future>(recorder: &DebuggingRecorder, f: F) -> F::Output {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    metrics::with_local_recorder(recorder, || rt.block_on(f))
+}
+
+/// Sends a file over a link damaging the sender's data
+async fn transfer(to_receiver: Impairments) {
+    let data = (0..LEN).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+    let (a, b) = link(to_receiver, Default::default(), 1);
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(data))];
+        // subpackets shrink, full ones rarely get through a damaging link
+        let options = send::Options { peer: "ttyS0".to_string(), adaptive: true, ..Default::default() };
+        send::send_files(a, files, &options).await.unwrap();
+    });
+
+    let (br, mut bw) = io::split(b);
+    let options = recv::Options { peer: "ttyS1".to_string(), timeout: Duration::from_secs(1), ..Default::default() };
+    recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(io::sink(), 0), &options).await.unwrap();
+    sender.await.unwrap();
+}
+
+#[test]
+fn session() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    run(&recorder, transfer(Default::default()));
+    let metrics = Metrics::new(&snapshotter);
+
+    let send = [("direction", "send"), ("peer", "ttyS0")];
+    let recv = [("direction", "recv"), ("peer", "ttyS1")];
+
+    assert!(metrics.counter("zmodem_bytes_sent_total", &send) >= LEN as u64);
+    assert!(metrics.counter("zmodem_bytes_received_total", &recv) >= LEN as u64);
+    assert_eq!(metrics.counter("zmodem_frames_sent_total", &[send[0], ("frame", "ZFILE")]), 1);
+    assert_eq!(metrics.counter("zmodem_frames_received_total", &[recv[0], ("frame", "ZFILE")]), 1);
+    assert_eq!(metrics.counter("zmodem_frames_sent_total", &[recv[0], ("frame", "ZRPOS")]), 1);
+    assert_eq!(metrics.counter("zmodem_rewinds_total", &[]), 0);
+    assert!(metrics.histogram("zmodem_subpacket_bytes", &send).iter().sum::<f64>() >= LEN as f64);
+
+    for labels in [send, recv] {
+        assert_eq!(metrics.counter("zmodem_sessions_total", &[labels[0], labels[1], ("outcome", "ok")]), 1);
+        assert_eq!(metrics.histogram("zmodem_session_duration_seconds", &labels).len(), 1);
+    }
+}
+
+#[test]
+fn damaged() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    run(&recorder, transfer(Impairments { bit_flips: 2e-4, ..Default::default() }));
+    let metrics = Metrics::new(&snapshotter);
+
+    assert!(metrics.counter("zmodem_crc_errors_total", &[("direction", "recv")]) >= 1);
+    assert!(metrics.counter("zmodem_rewinds_total", &[("direction", "recv")]) >= 1);
+    assert!(metrics.counter("zmodem_rewinds_total", &[("direction", "send")]) >= 1);
+}
+
+#[test]
+fn timeout() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    // nobody at the other end
+    let result = run(&recorder, async {
+        let (a, _b) = link(Default::default(), Default::default(), 1);
+        let options = recv::Options { timeout: Duration::from_millis(10), ..Default::default() };
+        recv::recv_files(a, |_| Action::Accept(io::sink(), 0), &options).await
+    });
+    assert!(result.is_err());
+
+    let metrics = Metrics::new(&snapshotter);
+    assert_eq!(metrics.counter("zmodem_timeouts_total", &[]), 10);
+    assert_eq!(metrics.counter("zmodem_sessions_total", &[("outcome", "timeout")]), 1);
+}
@@ -0,0 +1,243 @@
+//! Sessions replayed from recorded traces. The inbound side of a trace in
+//! `tests/fixtures` is fed to `send` or `recv`, what they write has to
+//! match the outbound side byte for byte. Run the ignored `record_fixtures`
+//! test to record the fixtures again after an intended protocol change.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::timeout;
+use zmodem::AsyncReadWrite;
+use zmodem::FileInfo;
+use zmodem::capture::{self, Capture, Direction, Format, Record};
+use zmodem::dump;
+use zmodem::recv::{self, Action};
+use zmodem::send::{self, Options};
+use zmodem::testing::{link, Impairments};
+
+/// Plays back the inbound records of a trace and collects what the session
+/// writes. Each record becomes readable once the session has written as
+/// much as it had when the record was read, so replies arrive at the same
+/// point of the session as they did on the wire.
+struct Replay {
+    /// Records with the length of the outbound stream they wait for
+    inbound:  VecDeque<(usize, Vec<u8>)>,
+    expected: Vec<u8>,
+    output:   Vec<u8>,
+    waker:    Option<Waker>,
+}
+
+impl Replay {
+    fn new(records: &[Record]) -> Replay {
+        let mut inbound = VecDeque::new();
+        let mut expected = Vec::new();
+
+        for x in records {
+            match x.direction {
+                Direction::In  => inbound.push_back((expected.len(), x.data.clone())),
+                Direction::Out => expected.extend_from_slice(&x.data),
+            }
+        }
+
+        Replay { inbound, expected, output: Vec::new(), waker: None }
+    }
+
+    /// Describes how the output differs from the recorded one
+    fn diff(&self) -> Option<String> {
+        if self.output != self.expected {
+            let at = self.output.iter().zip(&self.expected).take_while(|(x, y)| x == y).count();
+            let timeline = |data: &[u8]| dump::decode(data).into_iter()
+                .map(|x| format!("{:>10} {}", x.offset, x.kind))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            return Some(format!("output differs from the recording at byte {}\n--- expected\n{}\n--- actual\n{}",
+                                at, timeline(&self.expected), timeline(&self.output)));
+        }
+        if !self.inbound.is_empty() {
+            return Some(format!("{} inbound records left", self.inbound.len()));
+        }
+        None
+    }
+
+    fn check(&self) {
+        if let Some(e) = self.diff() {
+            panic!("{}", e);
+        }
+    }
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let written = self.output.len();
+
+        match self.inbound.front_mut() {
+            Some((after, data)) if *after <= written => {
+                let n = data.len().min(buf.remaining());
+                buf.put_slice(&data[..n]);
+                data.drain(..n);
+                if data.is_empty() {
+                    self.inbound.pop_front();
+                }
+                Poll::Ready(Ok(()))
+            },
+            Some(_) => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+            // the recording ended, so has the remote
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncWrite for Replay {
+    fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.output.extend_from_slice(buf);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name].iter().collect()
+}
+
+fn load(name: &str) -> Replay {
+    let f = File::open(fixture(name)).unwrap();
+    Replay::new(&capture::read_trace(BufReader::new(f), Format::JsonLines).unwrap())
+}
+
+/// Content of the transferred file, the same on every run
+fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn file_info(len: usize) -> FileInfo {
+    FileInfo {
+        size: Some(len as u32),
+        ..FileInfo::new("replay")
+    }
+}
+
+/// Sender with a small window talking to a receiver with a small buffer
+const SEND_SMALL_BUFFER: (&str, usize) = ("send_small_buffer.jsonl", 20_000);
+/// Receiver getting data damaged on the way
+const RECV_DAMAGED: (&str, usize) = ("recv_damaged.jsonl", 24_000);
+
+fn send_options() -> Options {
+    Options {
+        block_size: 1024,
+        window:     4096,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn replay_send() {
+    let (name, len) = SEND_SMALL_BUFFER;
+    let mut replay = load(name);
+
+    let files = [(file_info(len), Cursor::new(content(len)))];
+    let (_, stats) = send::send_files(&mut replay, files, &send_options()).await.unwrap();
+
+    replay.check();
+    assert_eq!(stats.max_block_size, 1024);
+}
+
+#[tokio::test]
+async fn replay_recv() {
+    let (name, len) = RECV_DAMAGED;
+    let mut replay = load(name);
+
+    let mut received = Vec::new();
+    let mut output = Some(&mut received);
+    let (_, stats) = recv::recv_files(&mut replay, |_| Action::Accept(output.take().unwrap(), 0), &Default::default())
+        .await.unwrap();
+
+    replay.check();
+    assert!(received == content(len));
+    assert!(stats.crc_errors >= 1);
+}
+
+#[tokio::test]
+async fn replay_mismatch() {
+    let (name, len) = SEND_SMALL_BUFFER;
+    let mut replay = load(name);
+
+    // a different file name changes the ZFILE subpacket, the recorded
+    // replies may never come
+    let files = [(FileInfo::new("other"), Cursor::new(content(len)))];
+    let _ = timeout(Duration::from_secs(1), send::send_files(&mut replay, files, &send_options())).await;
+
+    assert!(replay.diff().unwrap().starts_with("output differs from the recording at byte "));
+}
+
+#[tokio::test]
+#[ignore]
+async fn record_fixtures() {
+    std::fs::create_dir_all(fixture("")).unwrap();
+
+    // the sender, against a receiver advertising a 2048 byte buffer
+    let (name, len) = SEND_SMALL_BUFFER;
+    let (a, b) = io::duplex(16 * 1024);
+    let mut trace = Vec::new();
+
+    let receiver = tokio::spawn(async move {
+        let (br, mut bw) = io::split(b);
+        let options = recv::Options { buffer_size: 2048, ..Default::default() };
+        recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(io::sink(), 0), &options)
+            .await.unwrap();
+        // open for "OO"
+        bw
+    });
+
+    let files = [(file_info(len), Cursor::new(content(len)))];
+    send::send_files(Capture::new(a, &mut trace, Format::JsonLines), files, &send_options()).await.unwrap();
+    receiver.await.unwrap();
+    std::fs::write(fixture(name), &trace).unwrap();
+
+    // the receiver, with a link damaging the sender's data
+    let (name, len) = RECV_DAMAGED;
+    let damaged = Impairments { bit_flips: 5e-5, ..Default::default() };
+    let (a, b) = link(damaged, Default::default(), 1);
+    let mut trace = Vec::new();
+
+    let sender = tokio::spawn(async move {
+        let files = [(file_info(len), Cursor::new(content(len)))];
+        send::send_files(a, files, &Options::default()).await.unwrap();
+    });
+
+    let (br, mut bw) = io::split(b);
+    let rw = Capture::new(AsyncReadWrite::new(br, &mut bw), &mut trace, Format::JsonLines);
+    recv::recv_files(rw, |_| Action::Accept(io::sink(), 0), &Default::default()).await.unwrap();
+    sender.await.unwrap();
+    std::fs::write(fixture(name), &trace).unwrap();
+}
+
+#[test]
+fn binary_truncated() {
+    // a length of 4 GiB with 3 bytes behind it
+    let mut trace = vec![b'<'];
+    trace.extend_from_slice(&1500u64.to_le_bytes());
+    trace.extend_from_slice(&u32::MAX.to_le_bytes());
+    trace.extend_from_slice(b"**\x18");
+
+    let e = Record::read(&mut Cursor::new(trace), Format::Binary).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,63 @@
+//! Spans and events of the `tracing` feature
+
+use std::io::{self, Cursor, Write};
+use std::sync::{Arc, Mutex};
+use tracing::Level;
+use zmodem::{AsyncReadWrite, FileInfo};
+use zmodem::recv::{self, Action};
+use zmodem::send::{self, Options};
+use zmodem::testing::link;
+
+/// Collects the formatted events
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn session_spans() {
+    let events = Output::default();
+    let writer = events.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (a, b) = link(Default::default(), Default::default(), 1);
+
+    let sender = tokio::spawn(async move {
+        let options = Options { peer: "ttyS0".into(), ..Default::default() };
+        let files = [(FileInfo::new("data.bin"), Cursor::new(vec![0x2a; 1000]))];
+        send::send_files(a, files, &options).await.unwrap()
+    });
+
+    let (br, mut bw) = tokio::io::split(b);
+    let mut received = Vec::new();
+    let mut output = Some(&mut received);
+    let options = recv::Options { peer: "ttyS1".into(), ..Default::default() };
+    recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), 0), &options)
+        .await.unwrap();
+    sender.await.unwrap();
+    assert_eq!(received.len(), 1000);
+
+    let output = String::from_utf8(events.0.lock().unwrap().clone()).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    let find = |words: &[&str]| lines.iter().any(|x| words.iter().all(|w| x.contains(w)));
+
+    assert!(find(&["DEBUG", r#"direction="send""#, r#"peer="ttyS0""#, r#"file="data.bin""#, r#"frame="ZRPOS""#, "state=SendingData"]));
+    assert!(find(&["DEBUG", r#"direction="recv""#, r#"peer="ttyS1""#, r#"file="data.bin""#, r#"frame="ZDATA""#, "state=ReceivingData"]));
+    assert!(find(&["DEBUG", r#"direction="recv""#, "header sent", r#"frame="ZRPOS""#, "count=0"]));
+    assert!(find(&["TRACE", r#"direction="send""#, "subpacket sent", "len=1000"]));
+    assert!(find(&["TRACE", r#"direction="recv""#, "read", "data=2a2a2a"]));
+}
@@ -0,0 +1,135 @@
+//! Transfers over the impaired links of `zmodem::testing`
+
+mod common;
+
+use common::{init_log, RND_VALUES};
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io;
+use tokio::time::timeout;
+use zmodem::{AsyncReadWrite, FileInfo, Transport};
+use zmodem::recv::{self, Action};
+use zmodem::send::{self, Options, Statistics};
+use zmodem::testing::{link, Impairments};
+
+const LEN: usize = 100_000;
+
+/// Sends LEN bytes over a link damaging the sender's data, checks them at
+/// the receiver
+async fn transfer(to_receiver: Impairments, to_sender: Impairments, seed: u64) -> (Statistics, recv::Statistics) {
+    transfer_with(Options::default(), to_receiver, to_sender, seed).await
+}
+
+async fn transfer_with(options: Options, to_receiver: Impairments, to_sender: Impairments, seed: u64) -> (Statistics, recv::Statistics) {
+    init_log();
+
+    let (a, b) = link(to_receiver, to_sender, seed);
+    // don't wait long when a damaged header stalls both sides
+    let recv_options = recv::Options {
+        timeout:    Duration::from_secs(1),
+        transports: vec![options.transport],
+        ..Default::default()
+    };
+
+    let sender = tokio::spawn(async move {
+        let files = vec![(FileInfo::new("file"), Cursor::new(&RND_VALUES[..LEN]))];
+        send::send_files(a, files, &options).await.unwrap().1
+    });
+
+    let (br, mut bw) = io::split(b);
+    let mut received = Vec::new();
+    let mut output = Some(&mut received);
+    let session = recv::recv_files(AsyncReadWrite::new(br, &mut bw), |_| Action::Accept(output.take().unwrap(), 0), &recv_options);
+    let (_, stats) = timeout(Duration::from_secs(60), session).await
+        .expect("transfer doesn't converge").unwrap();
+
+    assert!(received[..] == RND_VALUES[..LEN]);
+    assert_eq!(stats.bytes, LEN as u64);
+    (sender.await.unwrap(), stats)
+}
+
+/// Damages the sender's data, the receiver has to notice and rewind.
+/// Subpackets shrink, full ones rarely get through and the receiver gives
+/// up after repeated errors at an offset.
+async fn damaged(impairments: Impairments) {
+    for seed in 1..=3 {
+        let options = Options { adaptive: true, ..Default::default() };
+        let (stats, recv_stats) = transfer_with(options, impairments.clone(), Default::default(), seed).await;
+        assert!(stats.rewinds >= 1, "seed {}", seed);
+        assert!(recv_stats.crc_errors + recv_stats.bad_headers >= 1, "seed {}", seed);
+    }
+}
+
+#[tokio::test]
+async fn bit_flips() {
+    damaged(Impairments { bit_flips: 2e-4, ..Default::default() }).await;
+}
+
+#[tokio::test]
+async fn drops() {
+    damaged(Impairments { drops: 1e-4, ..Default::default() }).await;
+}
+
+#[tokio::test]
+async fn dups() {
+    damaged(Impairments { dups: 1e-4, ..Default::default() }).await;
+}
+
+#[tokio::test]
+async fn noise() {
+    damaged(Impairments { noise: 1e-4, ..Default::default() }).await;
+}
+
+#[tokio::test]
+async fn xon_xoff() {
+    // the receiver ignores flow control, senders escape XON and XOFF
+    let (stats, recv_stats) = transfer(Impairments { xon_xoff: 1e-3, ..Default::default() }, Default::default(), 1).await;
+    assert_eq!(stats.rewinds, 0);
+    assert_eq!(recv_stats.crc_errors + recv_stats.bad_headers, 0);
+}
+
+#[tokio::test]
+async fn compressed() {
+    // rewinds are to offsets in uncompressed data
+    for transport in [Transport::Rle, Transport::Lzw] {
+        let options = Options { transport, adaptive: true, ..Default::default() };
+        let damaged = Impairments { bit_flips: 2e-4, ..Default::default() };
+        let (stats, _) = transfer_with(options, damaged, Default::default(), 1).await;
+        assert!(stats.rewinds >= 1, "{:?}", transport);
+    }
+}
+
+#[tokio::test]
+async fn latency() {
+    let slow = Impairments { latency: Duration::from_millis(20), ..Default::default() };
+    let (stats, _) = transfer(slow.clone(), slow, 1).await;
+    assert_eq!(stats.rewinds, 0);
+}
+
+#[tokio::test]
+async fn bandwidth() {
+    let slow = Impairments { bandwidth: Some(400_000), ..Default::default() };
+    let (stats, _) = transfer(slow.clone(), slow, 1).await;
+    assert_eq!(stats.rewinds, 0);
+}
+
+#[tokio::test]
+async fn same_seed_same_damage() {
+    let noisy = Impairments { noise: 1e-3, ..Default::default() };
+    let data = [0x55; 10_000];
+
+    let mut out = Vec::new();
+    for _ in 0..2 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut a, mut b) = link(noisy.clone(), Default::default(), 7);
+        a.write_all(&data).await.unwrap();
+        drop(a);
+        let mut buf = Vec::new();
+        b.read_to_end(&mut buf).await.unwrap();
+        out.push(buf);
+    }
+
+    assert!(out[0].len() > data.len());
+    assert_eq!(out[0], out[1]);
+}